@@ -0,0 +1,203 @@
+//! Admin HTTP API for operators to inspect and cancel matches,
+//! along with the public result API of completed matches
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post, put},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+use crate::game_pool::{GamePoolMessage, PoolStatus};
+use crate::queue::QueueClient;
+
+#[derive(Clone)]
+struct AdminState {
+    token: Arc<str>,
+    pool: mpsc::Sender<GamePoolMessage>,
+    queue_client: QueueClient,
+}
+
+/// Serve the admin API until the task is aborted.
+/// Every admin request must carry `Authorization: Bearer <token>`,
+/// match results are served without it.
+pub async fn serve(
+    port: u16,
+    token: &str,
+    pool: mpsc::Sender<GamePoolMessage>,
+    queue_client: QueueClient,
+) -> Result<()> {
+    let state = AdminState {
+        token: token.into(),
+        pool,
+        queue_client,
+    };
+    let admin = Router::new()
+        .route("/admin/games", get(list_games))
+        .route("/admin/games/{match_id}/cancel", post(cancel_game))
+        .route("/admin/pause", post(pause))
+        .route("/admin/resume", post(resume))
+        .route("/admin/max-concurrent", put(set_max_concurrent))
+        .route("/admin/reconnect", post(reconnect))
+        .layer(middleware::from_fn_with_state(state.clone(), require_token));
+    let app = Router::new()
+        .route("/games/{match_id}/result", get(game_result))
+        .merge(admin)
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(|e| anyhow!("Failed to bind admin port {}: {}", port, e))?;
+    info!("Serving admin API on port {}", port);
+
+    axum::serve(listener, app)
+        .await
+        .map_err(|e| anyhow!("Admin server failed: {}", e))
+}
+
+async fn require_token(
+    State(state): State<AdminState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| tokens_match(given, &state.token));
+    if !authorized {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(next.run(request).await)
+}
+
+/// Compare tokens in time independent of where they first differ
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// List the matches currently running, along with the backlog depth per priority
+async fn list_games(State(state): State<AdminState>) -> Result<Json<PoolStatus>, StatusCode> {
+    let (respond_to, response) = oneshot::channel();
+    state
+        .pool
+        .send(GamePoolMessage::QueryActive { respond_to })
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    response
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// The GameComplete message of a recently completed match,
+/// 404 if it is unknown or has expired
+async fn game_result(
+    State(state): State<AdminState>,
+    Path(match_id): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    let (respond_to, response) = oneshot::channel();
+    state
+        .pool
+        .send(GamePoolMessage::QueryResult {
+            match_id,
+            respond_to,
+        })
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    match response.await {
+        Ok(Some(result)) => Ok(Json(result)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+/// Stop starting games until resumed, letting running games finish
+async fn pause(State(state): State<AdminState>) -> Result<Json<Value>, StatusCode> {
+    set_accepting(&state, false).await
+}
+
+/// Start games again after a pause, beginning with those held meanwhile
+async fn resume(State(state): State<AdminState>) -> Result<Json<Value>, StatusCode> {
+    set_accepting(&state, true).await
+}
+
+async fn set_accepting(state: &AdminState, accepting: bool) -> Result<Json<Value>, StatusCode> {
+    state
+        .pool
+        .send(GamePoolMessage::SetAccepting(accepting))
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(json!({ "accepting": accepting })))
+}
+
+#[derive(Deserialize)]
+struct MaxConcurrent {
+    max_concurrent: usize,
+}
+
+/// Change how many games may run at once, 0 for no limit.
+/// Raising it starts pending games right away, lowering it aborts none.
+async fn set_max_concurrent(
+    State(state): State<AdminState>,
+    Json(body): Json<MaxConcurrent>,
+) -> Result<Json<Value>, StatusCode> {
+    state
+        .pool
+        .send(GamePoolMessage::SetMaxConcurrent(body.max_concurrent))
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(json!({ "max_concurrent": body.max_concurrent })))
+}
+
+/// Replace the broker connection with a fresh one, for instance after a
+/// broker failover. The consumer resumes on it under the same consumer tag.
+async fn reconnect(State(state): State<AdminState>) -> Result<Json<Value>, StatusCode> {
+    info!("Reconnecting to the queue cluster on operator request");
+    state.queue_client.reconnect().await.map_err(|e| {
+        error!("Failed to reconnect to the queue cluster: {}", e);
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    Ok(Json(json!({ "reconnected": true })))
+}
+
+/// Cancel a running or pending match, 404 if it is neither
+async fn cancel_game(
+    State(state): State<AdminState>,
+    Path(match_id): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    let (respond_to, response) = oneshot::channel();
+    state
+        .pool
+        .send(GamePoolMessage::CancelGame {
+            match_id: match_id.clone(),
+            respond_to,
+        })
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    match response.await {
+        Ok(Some(phase)) => Ok(Json(json!({
+            "match_id": match_id,
+            "cancelled": true,
+            "phase": phase,
+        }))),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
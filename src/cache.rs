@@ -0,0 +1,147 @@
+//! Bounded caches with time based expiry
+
+use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// A map whose entries expire a fixed time after insertion.
+/// Once `capacity` live entries are held, inserting evicts the oldest one.
+pub struct TtlCache<K, V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<K, (Instant, V)>,
+    /// Insertion order, used for both expiry and capacity eviction.
+    /// Records whose instant no longer matches the entry are stale and skipped.
+    order: VecDeque<(K, Instant)>,
+}
+
+impl<K: Eq + Hash + Clone, V> TtlCache<K, V> {
+    /// Create an empty cache
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Get a mutable reference to a live entry
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.evict_expired();
+        self.entries.get_mut(key).map(|(_, value)| value)
+    }
+
+    /// Whether a live entry exists for `key`
+    pub fn contains_key<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.get_mut(key).is_some()
+    }
+
+    /// Insert an entry, replacing any previous value and restarting its TTL
+    pub fn insert(&mut self, key: K, value: V) {
+        self.evict_expired();
+
+        if self.entries.remove(&key).is_none() {
+            while self.entries.len() >= self.capacity.max(1) {
+                match self.order.pop_front() {
+                    Some((oldest, inserted)) => self.remove_if_current(&oldest, inserted),
+                    None => break,
+                }
+            }
+        }
+
+        let now = Instant::now();
+        self.order.push_back((key.clone(), now));
+        self.entries.insert(key, (now, value));
+    }
+
+    fn evict_expired(&mut self) {
+        let now = Instant::now();
+        while let Some((_, inserted)) = self.order.front() {
+            if now.duration_since(*inserted) < self.ttl {
+                break;
+            }
+            if let Some((key, inserted)) = self.order.pop_front() {
+                self.remove_if_current(&key, inserted);
+            }
+        }
+    }
+
+    fn remove_if_current(&mut self, key: &K, inserted: Instant) {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|(current, _)| *current == inserted)
+        {
+            self.entries.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const TTL: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn expires_entries_after_ttl() {
+        let mut cache = TtlCache::new(TTL, 10);
+        cache.insert("a", 1);
+        assert!(cache.contains_key("a"));
+
+        sleep(TTL + Duration::from_millis(20));
+        assert!(!cache.contains_key("a"));
+    }
+
+    #[test]
+    fn evicts_oldest_entry_at_capacity() {
+        let mut cache = TtlCache::new(TTL * 100, 2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+
+        assert!(!cache.contains_key("a"));
+        assert_eq!(cache.get_mut("b"), Some(&mut 2));
+        assert_eq!(cache.get_mut("c"), Some(&mut 3));
+    }
+
+    #[test]
+    fn reinserting_restarts_ttl() {
+        let mut cache = TtlCache::new(TTL, 10);
+        cache.insert("a", 1);
+        sleep(TTL * 6 / 10);
+        cache.insert("a", 2);
+
+        // Past the first insertion's TTL, its stale record must not expire the new value
+        sleep(TTL * 6 / 10);
+        assert_eq!(cache.get_mut("a"), Some(&mut 2));
+
+        sleep(TTL * 6 / 10);
+        assert!(!cache.contains_key("a"));
+    }
+
+    #[test]
+    fn reinserted_entry_is_not_evicted_by_its_stale_record() {
+        let mut cache = TtlCache::new(TTL * 100, 2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("a", 3);
+        cache.insert("c", 4);
+
+        // "a" was inserted first, but "b" is the oldest live entry
+        assert_eq!(cache.get_mut("a"), Some(&mut 3));
+        assert!(!cache.contains_key("b"));
+        assert_eq!(cache.get_mut("c"), Some(&mut 4));
+    }
+}
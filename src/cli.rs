@@ -1,4 +1,7 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+use crate::game::Ruleset;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -6,9 +9,22 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Command>,
 
-    /// Perform a health check and exit
-    #[clap(long)]
-    pub health_check: bool,
+    /// Perform a health check and exit, readiness unless liveness is given
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "readiness")]
+    pub health_check: Option<HealthCheck>,
+
+    /// TOML config file, overridden by environment variables.
+    /// Defaults to the CONFIG_FILE environment variable when set.
+    #[clap(long, global = true)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum HealthCheck {
+    /// Config loads and the queue cluster accepts a connection
+    Liveness,
+    /// Liveness, plus the incoming exchange and queue are usable for consuming
+    Readiness,
 }
 
 #[derive(Subcommand, Debug)]
@@ -24,9 +40,41 @@ pub enum Command {
 
 #[derive(Subcommand, Debug)]
 pub enum Tool {
-    /// Queue a match and wait for the result
+    /// Queue a match and wait for the result, or a batch of matches to benchmark the service
     QueueMatch {
-        /// The players to include in the match
+        /// The players to include in each match, bots fill any remaining seats
+        #[clap(num_args = 0..=4)]
+        players: Vec<String>,
+
+        /// Routing key to publish the match under, matched against instances' binding keys
+        #[clap(long, default_value = "")]
+        routing_key: String,
+
+        /// Seconds to wait for the match result before giving up
+        #[clap(long, default_value_t = 60)]
+        timeout_secs: u64,
+
+        /// Number of matches to queue, reporting their timing rather than their results
+        #[clap(long, default_value_t = 1)]
+        count: usize,
+
+        /// Most matches awaiting their result at once, all of them when unset
+        #[clap(long)]
+        concurrency: Option<usize>,
+    },
+    /// Print the loaded configuration as JSON, with credentials redacted
+    DumpConfig,
+    /// Run a match locally from a seed, printing every state, without the queue
+    Replay {
+        /// The seed the original match ran with
+        #[clap(long)]
+        seed: u64,
+
+        /// The rules the original match ran under
+        #[clap(long, default_value = "standard")]
+        ruleset: Ruleset,
+
+        /// The players of the original match, in seat order
         #[clap(required = true, num_args = 1..=4)]
         players: Vec<String>,
     },
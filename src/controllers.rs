@@ -1,5 +1,75 @@
+use std::str::FromStr;
+
+use crate::game::GameError;
+
+/// Longest player name accepted in a GameStarting message, in bytes
+pub const MAX_PLAYER_NAME_LEN: usize = 64;
+
+/// Play style of an embedded bot. Each is a separate controller registration
+/// in the engine, named in GameStarting messages by `name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BotStrategy {
+    /// Discards its most recently drawn tile, seated in empty seats by default
+    #[default]
+    AngryDiscardo,
+    Gentleman,
+    Thrice,
+    Toto,
+}
+
+impl BotStrategy {
+    const ALL: [BotStrategy; 4] = [
+        BotStrategy::AngryDiscardo,
+        BotStrategy::Gentleman,
+        BotStrategy::Thrice,
+        BotStrategy::Toto,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BotStrategy::AngryDiscardo => "angry_discardo",
+            BotStrategy::Gentleman => "gentleman",
+            BotStrategy::Thrice => "thrice",
+            BotStrategy::Toto => "toto",
+        }
+    }
+
+    /// Name the engine registered the bot's controller under
+    pub fn controller_name(self) -> &'static str {
+        match self {
+            BotStrategy::AngryDiscardo => "AngryDiscardoBot",
+            BotStrategy::Gentleman => "GentlemanBot",
+            BotStrategy::Thrice => "ThriceBot",
+            BotStrategy::Toto => "TotoBot",
+        }
+    }
+
+    /// The strategy whose controller is registered as `name`, if any
+    fn from_controller_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|strategy| strategy.controller_name() == name)
+    }
+}
+
+impl FromStr for BotStrategy {
+    type Err = GameError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|strategy| strategy.name() == name)
+            .ok_or_else(|| GameError::UnknownBot(name.to_string()))
+    }
+}
+
 pub enum GameController {
-    Embedded(String),
+    /// A controller running in the engine: the seated player's own
+    /// controller, or the bot registered for `strategy` when given
+    Embedded {
+        name: String,
+        strategy: Option<BotStrategy>,
+    },
     /// TODO
     ///
     /// Implement network based controller once libmahjong-rs supports
@@ -8,10 +78,58 @@ pub enum GameController {
     External,
 }
 
+/// Check that `players` and the `fill_bots` for their empty seats fit in a
+/// match: at most 4, each player seated once. Bots may take several seats,
+/// as they would when padding.
+pub fn validate_players(players: &[String], fill_bots: &[BotStrategy]) -> Result<(), GameError> {
+    if players.len() > 4 {
+        return Err(GameError::TooManyPlayers(players.len()));
+    }
+    if players.len() + fill_bots.len() > 4 {
+        return Err(GameError::TooManyFillBots(
+            fill_bots.len(),
+            4 - players.len(),
+        ));
+    }
+    for (i, player) in players.iter().enumerate() {
+        let bot = BotStrategy::from_controller_name(player).is_some();
+        if !bot && players[..i].contains(player) {
+            return Err(GameError::DuplicatePlayer(player.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Seat `players` in order as embedded controllers, filling the remaining seats
+/// with `fill_bots` in order and any seats still empty with the default bot.
+/// Players past the fourth are not seated, `validate_players` rejects them.
+pub fn seat_players(players: &[String], fill_bots: &[BotStrategy]) -> [GameController; 4] {
+    std::array::from_fn(|i| match players.get(i) {
+        Some(name) => GameController::Embedded {
+            name: name.clone(),
+            strategy: None,
+        },
+        None => {
+            let strategy = fill_bots
+                .get(i - players.len())
+                .copied()
+                .unwrap_or_default();
+            GameController::Embedded {
+                name: strategy.controller_name().to_string(),
+                strategy: Some(strategy),
+            }
+        }
+    })
+}
+
 impl ToString for GameController {
     fn to_string(&self) -> String {
         match self {
-            GameController::Embedded(name) => name.clone(),
+            GameController::Embedded {
+                strategy: Some(strategy),
+                ..
+            } => strategy.controller_name().to_string(),
+            GameController::Embedded { name, .. } => name.clone(),
             GameController::External => "External".to_string(),
         }
     }
@@ -0,0 +1,37 @@
+//! Helpers for the files matches are kept in on disk
+
+use std::io;
+use std::path::Path;
+
+use crate::hash::fnv1a;
+
+/// Characters of a match id kept in a file name, leaving room for the hash,
+/// prefixes and extensions within the usual 255 byte file name limit
+const MAX_STEM_CHARS: usize = 64;
+
+/// File name stem for `match_id`. Match ids come from callers, so only
+/// characters safe in a file name are kept, and few enough of them to stay
+/// within file name limits. A hash of the full id tells apart ids that only
+/// differ in the others.
+pub fn safe_file_stem(match_id: &str) -> String {
+    let safe_id: String = match_id
+        .chars()
+        .take(MAX_STEM_CHARS)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}-{:016x}", safe_id, fnv1a(match_id.bytes()))
+}
+
+/// Write `contents` to `path` under a temporary name and rename it into place,
+/// so a reader or a crash never leaves a partially written file behind
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    tokio::fs::write(&partial, contents).await?;
+    tokio::fs::rename(&partial, path).await
+}
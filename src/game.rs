@@ -1,14 +1,162 @@
-use anyhow::Result;
 use libmahjong_rs::{
     ffi::{error::MahjongFFIError, gamestate::GameState},
     observe::{ObservedGameState, StateFunctionType},
     settings::GameSettings,
 };
-use rand::Rng;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::{debug, info};
 
 use crate::controllers::GameController;
 
+/// Failures creating or running a game
+#[derive(Debug, Error)]
+pub enum GameError {
+    #[error("Match has {0} players, at most 4 can be seated")]
+    TooManyPlayers(usize),
+    #[error("Player '{0}' is listed more than once")]
+    DuplicatePlayer(String),
+    #[error("{0} fill bots requested for {1} empty seats")]
+    TooManyFillBots(usize, usize),
+    #[error("Unknown bot strategy '{0}'")]
+    UnknownBot(String),
+    #[error("Unknown ruleset '{0}'")]
+    UnknownRuleset(String),
+    #[error("Attempted to advance a finished game")]
+    AlreadyFinished,
+    #[error("Game panicked: {0}")]
+    Panicked(String),
+    #[error(transparent)]
+    Engine(#[from] MahjongFFIError),
+}
+
+/// Rule variant a match is played under, named in GameStarting and GameComplete messages.
+/// Only the name is plumbed through for now: the engine bindings' `GameSettings`
+/// takes nothing but seat controllers and a seed, so `Standard` is the only variant.
+/// Variants such as red fives or game length are blocked on the bindings exposing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ruleset {
+    #[default]
+    Standard,
+}
+
+impl Ruleset {
+    pub fn name(self) -> &'static str {
+        match self {
+            Ruleset::Standard => "standard",
+        }
+    }
+}
+
+impl FromStr for Ruleset {
+    type Err = GameError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "standard" => Ok(Ruleset::Standard),
+            other => Err(GameError::UnknownRuleset(other.to_string())),
+        }
+    }
+}
+
+/// How much of its turn history a game keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryRetention {
+    /// Every observed state, for the whole game
+    All,
+    /// Only the given number of most recent states
+    Latest(usize),
+}
+
+/// Observed states a game went through, oldest first
+pub struct TurnHistory {
+    turns: VecDeque<ObservedGameState>,
+    retention: HistoryRetention,
+    /// Older turns discarded to stay within the retention limit
+    dropped: u64,
+}
+
+impl TurnHistory {
+    fn new(retention: HistoryRetention) -> Self {
+        Self {
+            turns: VecDeque::new(),
+            retention,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, observed: ObservedGameState) {
+        if let HistoryRetention::Latest(limit) = self.retention {
+            if limit == 0 {
+                self.dropped += 1;
+                return;
+            }
+            if self.turns.len() >= limit {
+                self.turns.pop_front();
+                self.dropped += 1;
+            }
+        }
+        self.turns.push_back(observed);
+    }
+
+    /// Recorded turns, oldest first
+    pub fn turns(&self) -> impl ExactSizeIterator<Item = &ObservedGameState> {
+        self.turns.iter()
+    }
+
+    /// Number of turns played before the first recorded one
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// What is reported of an observed engine state in GameComplete messages,
+/// turn history and the state stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StateSnapshot {
+    /// Engine state the game was in, e.g. `GameEnd`
+    pub state: String,
+    /// Points held by each seat, in seat order
+    pub scores: [i32; 4],
+}
+
+impl StateSnapshot {
+    /// Seat holding the most points, the earliest of them on a tie
+    pub fn leader(&self) -> usize {
+        (0..self.scores.len())
+            .max_by_key(|&seat| (self.scores[seat], std::cmp::Reverse(seat)))
+            .unwrap_or(0)
+    }
+}
+
+impl From<&ObservedGameState> for StateSnapshot {
+    fn from(observed: &ObservedGameState) -> Self {
+        Self {
+            state: format!("{:?}", observed.current_state()),
+            scores: observed.scores(),
+        }
+    }
+}
+
+/// Hook called with each state a game reaches
+type StateObserver = Box<dyn FnMut(&ObservedGameState)>;
+
+/// Result of advancing a game by one step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvanceOutcome {
+    /// The game stepped and can be advanced again immediately
+    Continued,
+    /// The game is blocked on a controller that has not provided its decision yet.
+    /// Only external controllers wait, embedded ones always decide immediately.
+    #[allow(dead_code)]
+    AwaitingInput,
+    /// The game is over
+    Finished,
+}
+
 /// Represents a single game match to execute
 /// Libmahjong matches are an iterated on state machine,
 /// which has hooks to it's game controllers.
@@ -17,56 +165,106 @@ use crate::controllers::GameController;
 ///
 /// Game matches should be iterated to completion
 pub struct GameMatch {
+    /// The engine state, `None` once the game has ended or failed to advance.
+    /// A game that returned an error from `advance` is never advanced again.
     state: Option<GameState>,
     match_id: String,
+    /// Called with the observed state after every successful advance
+    observer: Option<StateObserver>,
+    /// Every observed state, when recording is enabled
+    history: Option<TurnHistory>,
+    /// Kind of the state last advanced to, for logging transitions
+    last_state: Option<StateFunctionType>,
 }
 
 impl GameMatch {
-    /// Try to create a new game match
-    pub fn try_new(match_id: String, controllers: Vec<GameController>) -> Result<Self> {
-        let controller_strings: Vec<String> = controllers.iter().map(|c| c.to_string()).collect();
-        let seat_controllers: [String; 4] = controller_strings
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("Expected exactly 4 controllers"))?;
-
-        let settings = GameSettings {
-            seat_controllers,
-            seed: rand::thread_rng().gen(),
+    /// Try to create a new game match under `ruleset`, seeding the engine with `seed`
+    pub fn try_new(
+        match_id: String,
+        controllers: [GameController; 4],
+        seed: u64,
+        ruleset: Ruleset,
+    ) -> Result<Self, GameError> {
+        let seat_controllers = controllers.map(|c| c.to_string());
+
+        let settings = match ruleset {
+            Ruleset::Standard => GameSettings {
+                seat_controllers,
+                seed,
+            },
         };
 
         Ok(Self {
             state: Some(GameState::new(settings)?),
             match_id,
+            observer: None,
+            history: None,
+            last_state: None,
         })
     }
 
-    /// Advance the game state
-    pub fn advance(&mut self) -> Result<bool> {
+    /// Register a hook receiving the observed state after every successful advance
+    pub fn on_advance(&mut self, observer: impl FnMut(&ObservedGameState) + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Start recording the observed state after every successful advance,
+    /// keeping as much of it as `retention` allows
+    pub fn record_history(&mut self, retention: HistoryRetention) {
+        self.history = Some(TurnHistory::new(retention));
+    }
+
+    /// Turns recorded so far, if recording is enabled
+    pub fn history(&self) -> Option<&TurnHistory> {
+        self.history.as_ref()
+    }
+
+    /// Advance the game state. On any error the state is cleared,
+    /// so later calls fail with `GameError::AlreadyFinished`.
+    pub fn advance(&mut self) -> Result<AdvanceOutcome, GameError> {
         if let Some(current_state) = self.state.take() {
             match current_state.advance() {
                 Ok(new_state) => {
+                    let Some(observed) = new_state.observe() else {
+                        // The state cannot be inspected, so it cannot be trusted to advance either
+                        return Err(MahjongFFIError::GameStateConsumed.into());
+                    };
                     self.state = Some(new_state);
-                    let observed = self
-                        .observe_state()
-                        .ok_or(MahjongFFIError::GameStateConsumed)?;
-                    if observed.current_state() == StateFunctionType::GameEnd {
+                    let to = observed.current_state();
+                    match self.last_state.replace(to) {
+                        Some(from) => {
+                            debug!("Game {} advanced {:?} -> {:?}", self.match_id, from, to)
+                        }
+                        None => debug!("Game {} advanced to {:?}", self.match_id, to),
+                    }
+                    if let Some(observer) = &mut self.observer {
+                        observer(&observed);
+                    }
+                    let finished = to == StateFunctionType::GameEnd;
+                    if finished {
                         info!("Game {} finished: {:?}", self.match_id, observed);
-                        return Ok(false); // Game is done
+                    }
+                    if let Some(history) = &mut self.history {
+                        history.push(observed);
                     }
 
-                    Ok(true) // Game continues
+                    if finished {
+                        Ok(AdvanceOutcome::Finished)
+                    } else {
+                        Ok(AdvanceOutcome::Continued)
+                    }
                 }
                 Err(MahjongFFIError::GameEnded) => {
                     // Game is finished, state remains None
-                    Ok(false) // Game is done
+                    Ok(AdvanceOutcome::Finished)
                 }
                 Err(e) => {
-                    // Propagate other errors
+                    // Propagate other errors, the consumed state stays cleared
                     Err(e.into())
                 }
             }
         } else {
-            Err(anyhow::anyhow!("Attempted to advance a finished game"))
+            Err(GameError::AlreadyFinished)
         }
     }
 
@@ -75,3 +273,26 @@ impl GameMatch {
         self.state.as_ref().and_then(|s| s.observe())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leader_holds_the_most_points() {
+        let snapshot = StateSnapshot {
+            state: "GameEnd".to_string(),
+            scores: [25_000, 32_000, 18_000, 25_000],
+        };
+        assert_eq!(snapshot.leader(), 1);
+    }
+
+    #[test]
+    fn earliest_seat_leads_on_a_tie() {
+        let snapshot = StateSnapshot {
+            state: "GameEnd".to_string(),
+            scores: [20_000, 30_000, 20_000, 30_000],
+        };
+        assert_eq!(snapshot.leader(), 1);
+    }
+}
@@ -1,58 +1,605 @@
 //! Game pool management for handling multiple concurrent matches
 
-use anyhow::Result;
-use libmahjong_rs::observe::StateFunctionType;
-use serde_json::json;
-use std::collections::HashMap;
-use std::ops::Rem;
-use tokio::sync::mpsc;
-use tokio::task::{spawn_blocking, JoinHandle};
-use tracing::{error, info, warn};
-
-use crate::controllers::GameController;
-use crate::game::GameMatch;
-use crate::queue::QueueClient;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, mpsc::error::TrySendError, oneshot};
+use tracing::{debug, error, info, instrument, warn, Instrument, Span};
+
+use crate::cache::TtlCache;
+use crate::config::Config;
+use crate::controllers::{self, BotStrategy};
+use crate::game::{HistoryRetention, Ruleset, StateSnapshot};
+use crate::hash::fnv1a;
+use crate::metrics;
+use crate::queue::{Backoff, Encoding, QueueClient, QueueError, SCHEMA_VERSION};
+use crate::spill::CompletionSpill;
+use crate::store::{FileMatchStore, MatchCheckpoint, MatchStore};
+use crate::workers::{EngineRunner, GameJob, GameRunner, Waker, WorkerPool};
+
+/// Longest match id accepted, as it becomes the routing key of the GameComplete
+/// message and AMQP limits routing keys to 255 bytes
+pub const MAX_MATCH_ID_LEN: usize = 255;
+/// How long the idempotency key of a finished match is remembered
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(60 * 60);
+/// Upper bound on the number of finished matches' idempotency keys remembered
+/// at once. Keys of matches still in the pool are never evicted.
+const IDEMPOTENCY_CAPACITY: usize = 10_000;
+/// How long a finished match id is remembered to drop redelivered GameStarting messages
+const RECENT_MATCH_TTL: Duration = Duration::from_secs(60 * 60);
+/// Upper bound on the number of finished match ids remembered at once
+const RECENT_MATCH_CAPACITY: usize = 10_000;
+/// Game state updates buffered for slow stream subscribers before they miss some
+const STATE_STREAM_CAPACITY: usize = 1024;
+/// How often running games are checked against the game timeout
+const TIMEOUT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Times publishing a GameComplete message is tried before it is spilled to disk
+const COMPLETION_PUBLISH_ATTEMPTS: u32 = 3;
+/// Delay before the first GameComplete publish retry, doubled on each later one
+const COMPLETION_RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+const COMPLETION_RETRY_MAX_DELAY: Duration = Duration::from_secs(2);
+/// How often spilled GameComplete messages are republished
+const SPILL_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Failures of the game pool and its workers
+#[derive(Debug, Error)]
+pub enum PoolError {
+    #[error("Failed to spawn game worker {index}: {error}")]
+    WorkerSpawn { index: usize, error: std::io::Error },
+    /// No worker could take on the game
+    #[error("Game worker has stopped, could not start {0}")]
+    WorkersStopped(String),
+    #[error(transparent)]
+    Queue(#[from] QueueError),
+    #[error("Failed to encode message: {0}")]
+    Encode(#[from] serde_json::Error),
+    #[error("Failed to spill GameComplete message to disk: {0}")]
+    Spill(std::io::Error),
+    #[error("Failed to open match store: {0}")]
+    Store(std::io::Error),
+    #[error(transparent)]
+    InvalidRequest(#[from] RequestError),
+    #[error("Game pool is busy, match {0} was not submitted")]
+    Busy(String),
+    #[error("Game pool has stopped, match {0} was not submitted")]
+    Stopped(String),
+}
+
+/// A GameStarting message that does not describe a playable match
+#[derive(Debug, Error)]
+pub enum RequestError {
+    #[error("GameStarting message has an empty match_id")]
+    EmptyMatchId,
+    #[error("GameStarting match_id is {0} bytes long, at most {MAX_MATCH_ID_LEN} are allowed")]
+    MatchIdTooLong(usize),
+    #[error("GameStarting player {0} has an empty name")]
+    EmptyPlayerName(usize),
+    #[error(
+        "GameStarting player {seat} name is {len} bytes long, at most {} are allowed",
+        controllers::MAX_PLAYER_NAME_LEN
+    )]
+    PlayerNameTooLong { seat: usize, len: usize },
+}
+
+/// A request to play a match, the body of a GameStarting message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartGameRequest {
+    pub match_id: String,
+    /// Players seated in order, bots take the remaining seats
+    #[serde(default)]
+    pub players: Vec<String>,
+    /// Names of the bot strategies to seat in empty seats, in seat order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fill_bots: Vec<String>,
+    /// Caller supplied key identifying the logical match across resubmissions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Engine seed to replay a specific game, random when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Rule variant to play, the standard rules when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ruleset: Option<Ruleset>,
+    /// Higher priority matches start first when the pool is at capacity
+    #[serde(default)]
+    pub priority: i32,
+    /// Which submission of the match this is, starting at 1
+    #[serde(default = "first_attempt")]
+    pub attempt: u32,
+}
+
+fn first_attempt() -> u32 {
+    1
+}
+
+impl StartGameRequest {
+    /// Request a first attempt at a match with default settings
+    pub fn new(match_id: impl Into<String>, players: Vec<String>) -> Self {
+        Self {
+            match_id: match_id.into(),
+            players,
+            fill_bots: Vec::new(),
+            idempotency_key: None,
+            seed: None,
+            ruleset: None,
+            priority: 0,
+            attempt: first_attempt(),
+        }
+    }
+
+    /// Check the parts of the request the game pool cannot reject on its own:
+    /// a match id usable as a routing key, and player names of bounded length
+    pub fn validate(&self) -> Result<(), RequestError> {
+        if self.match_id.trim().is_empty() {
+            return Err(RequestError::EmptyMatchId);
+        }
+        if self.match_id.len() > MAX_MATCH_ID_LEN {
+            return Err(RequestError::MatchIdTooLong(self.match_id.len()));
+        }
+        for (seat, name) in self.players.iter().enumerate() {
+            if name.trim().is_empty() {
+                return Err(RequestError::EmptyPlayerName(seat));
+            }
+            if name.len() > controllers::MAX_PLAYER_NAME_LEN {
+                return Err(RequestError::PlayerNameTooLong {
+                    seat,
+                    len: name.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate the request and hand it to the game pool as a GameStarting
+    /// message delivered with `routing_key` and `correlation_id`, failing with
+    /// `Busy` rather than waiting when the pool is behind
+    pub fn submit(
+        self,
+        pool: &mpsc::Sender<GamePoolMessage>,
+        routing_key: &str,
+        correlation_id: Option<&str>,
+    ) -> Result<(), PoolError> {
+        self.validate()?;
+        let match_id = self.match_id.clone();
+        pool.try_send(GamePoolMessage::StartGame {
+            request: self,
+            routing_key: routing_key.to_string(),
+            correlation_id: correlation_id.map(str::to_string),
+            enqueued_at: Instant::now(),
+        })
+        .map_err(|e| match e {
+            TrySendError::Full(_) => PoolError::Busy(match_id),
+            TrySendError::Closed(_) => PoolError::Stopped(match_id),
+        })
+    }
+}
 
 /// Messages sent to the game pool for coordination
 #[derive(Debug)]
 pub enum GamePoolMessage {
     /// External command to start a new game
     StartGame {
-        match_id: String,
-        players: Vec<String>,
+        request: StartGameRequest,
+        /// Routing key the GameStarting message was published under, reused for retries
+        routing_key: String,
+        /// Correlation id of the GameStarting message, carried over to the GameComplete message
+        correlation_id: Option<String>,
+        /// When the match was accepted by intake
+        enqueued_at: Instant,
     },
     /// Internal notification that a game completed successfully
-    GameComplete { match_id: String },
+    GameComplete {
+        match_id: String,
+        result: GameResult,
+        finished_at: Instant,
+    },
+    /// Internal notification that a game could not be created
+    GameStartFailed { match_id: String, error: String },
     /// Internal notification that a game ended in an error
-    GameError { match_id: String, error: String },
+    GameError {
+        match_id: String,
+        error: String,
+        finished_at: Instant,
+    },
+    /// Command to stop accepting games and shut down once active games finish,
+    /// aborting any still running at the deadline
+    Drain { deadline: Instant },
     /// Command to shut down the entire game pool
     Shutdown,
+    /// Query the matches currently running in the pool
+    QueryActive {
+        respond_to: oneshot::Sender<PoolStatus>,
+    },
+    /// Cancel a running match, publishing a cancelled completion event.
+    /// Responds with whether the match was running.
+    CancelGame {
+        match_id: String,
+        /// Whether the match was running or still pending, `None` if it was neither
+        respond_to: oneshot::Sender<Option<MatchPhase>>,
+    },
+    /// Pause or resume starting games. While paused, matches are accepted but
+    /// held in the pending queue, running games are left to finish.
+    SetAccepting(bool),
+    /// Change how many games may run at once, 0 for no limit. Lowering it
+    /// never aborts running games, new ones wait until enough have finished.
+    SetMaxConcurrent(usize),
+    /// Query the GameComplete message of a recently completed match,
+    /// `None` if it is unknown or no longer remembered
+    QueryResult {
+        match_id: String,
+        respond_to: oneshot::Sender<Option<Value>>,
+    },
+}
+
+/// Running matches and backlog, as reported by `GamePoolMessage::QueryActive`
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStatus {
+    /// Whether pending games are being started, false while paused
+    pub accepting: bool,
+    /// Most games run at once, 0 for no limit
+    pub max_concurrent: usize,
+    pub active_count: usize,
+    pub active: Vec<MatchInfo>,
+    pub pending_count: usize,
+    /// Number of matches waiting for a free slot, by priority
+    pub pending: BTreeMap<i32, usize>,
+    /// Matches waiting for a free slot, in the order they will start
+    pub pending_matches: Vec<PendingMatchInfo>,
+}
+
+/// Whether a match has started, as reported when cancelling it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchPhase {
+    /// Accepted but waiting for a free slot
+    Pending,
+    /// Running on the worker pool
+    Active,
+}
+
+/// Summary of a match waiting for a free slot, as reported by `GamePoolMessage::QueryActive`
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingMatchInfo {
+    pub match_id: String,
+    pub players: Vec<String>,
+    pub priority: i32,
+}
+
+/// Summary of a running match, as reported by `GamePoolMessage::QueryActive`
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchInfo {
+    pub match_id: String,
+    pub players: Vec<String>,
+    pub started_at: DateTime<Utc>,
+}
+
+/// A game state observed after a step of a running game
+#[derive(Debug, Clone, Serialize)]
+pub struct StateUpdate {
+    pub match_id: String,
+    /// Number of successful advances so far, starting at 1
+    pub step: u64,
+    pub state: StateSnapshot,
 }
 
 /// Final status reported by a sync game runner
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum GameStatus {
-    Finished,
+    Finished(GameResult),
+    /// The game could not be created, nothing was played
+    StartFailed(String),
     Error(String),
 }
 
+/// Outcome of a finished game, included in its GameComplete event
+#[derive(Debug, Clone)]
+pub struct GameResult {
+    /// Controller name of each seat, in seat order
+    pub seats: Vec<String>,
+    /// State the game ended in, with the final scores
+    pub final_state: Option<StateSnapshot>,
+    /// States the game went through, when history recording is enabled
+    pub history: Option<TurnLog>,
+}
+
+/// Recorded turn history of a finished game
+#[derive(Debug, Clone)]
+pub struct TurnLog {
+    /// Each recorded state, oldest first
+    pub turns: Vec<StateSnapshot>,
+    /// Turns played before the first recorded one, dropped to stay within the history limit
+    pub dropped: u64,
+}
+
+/// A game accepted by the pool but waiting for a free slot
+struct PendingGame {
+    match_id: String,
+    players: Vec<String>,
+    fill_bots: Vec<BotStrategy>,
+    idempotency_key: Option<String>,
+    seed: Option<u64>,
+    ruleset: Ruleset,
+    priority: i32,
+    /// Arrival order among pending games, breaking ties between equal priorities
+    sequence: u64,
+    attempt: u32,
+    routing_key: String,
+    correlation_id: Option<String>,
+    enqueued_at: Instant,
+}
+
+impl PendingGame {
+    /// The request the match was submitted with
+    fn into_request(self) -> StartGameRequest {
+        StartGameRequest {
+            match_id: self.match_id,
+            players: self.players,
+            fill_bots: self
+                .fill_bots
+                .iter()
+                .map(|bot| bot.name().to_string())
+                .collect(),
+            idempotency_key: self.idempotency_key,
+            seed: self.seed,
+            ruleset: Some(self.ruleset),
+            priority: self.priority,
+            attempt: self.attempt,
+        }
+    }
+}
+
+/// Pending games are ordered by priority, then by arrival, so the
+/// max-heap of them yields the earliest of the highest priority games
+impl Ord for PendingGame {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for PendingGame {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for PendingGame {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for PendingGame {}
+
+/// A game currently running in the pool
+struct ActiveGame {
+    /// Asks the worker running the game to stop at its next step
+    cancelled: Arc<AtomicBool>,
+    /// Steps the worker has advanced the game by
+    steps: Arc<AtomicU64>,
+    players: Vec<String>,
+    fill_bots: Vec<BotStrategy>,
+    idempotency_key: Option<String>,
+    seed: u64,
+    ruleset: Ruleset,
+    priority: i32,
+    attempt: u32,
+    routing_key: String,
+    correlation_id: Option<String>,
+    enqueued_at: Instant,
+    started_at: Instant,
+    /// Wall clock counterpart of `started_at`, for reporting
+    start_time: DateTime<Utc>,
+    /// Wakes the worker running the game, set once the game is handed to one
+    waker: Option<Waker>,
+}
+
+impl ActiveGame {
+    /// Ask the worker running the game to stop it, waking the worker
+    /// in case the game is waiting on input
+    fn stop(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        if let Some(waker) = &self.waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Where a match spent its time, reported when timing is enabled
+#[derive(Debug)]
+struct GameTiming {
+    /// Enqueued to started
+    queue_wait: Duration,
+    /// Started to finished
+    run: Duration,
+    /// When the game finished, to time publishing its completion event
+    finished_at: Instant,
+}
+
+/// How a running game came to an end
+enum GameEnding {
+    Finished(GameResult),
+    /// Stopped by an operator
+    Cancelled,
+    /// Ran for longer than the game timeout
+    TimedOut(Duration),
+    /// The game failed to start or to advance
+    Failed(String),
+}
+
+/// Optional extras included in a GameComplete event
+#[derive(Debug, Default)]
+struct CompletionDetails {
+    /// The original match when answering a resubmission
+    duplicate_of: Option<String>,
+    /// Why the match was rejected without being played
+    rejected: Option<String>,
+    /// Whether the match was cancelled by an operator
+    cancelled: bool,
+    /// Whether the game was stopped for running past the game timeout
+    timed_out: bool,
+    /// Why the game ended without a result, when it timed out or failed
+    error: Option<String>,
+    /// Seed the game ran with, so it can be replayed
+    seed: Option<u64>,
+    /// Rules the game ran under
+    ruleset: Option<Ruleset>,
+    /// Correlation id of the GameStarting message being answered
+    correlation_id: Option<String>,
+    result: Option<GameResult>,
+    timing: Option<GameTiming>,
+}
+
+/// What the pool remembers about an unfinished match submitted with an idempotency key
+struct IdempotencyRecord {
+    /// The match that was actually started for this key
+    match_id: String,
+    /// Resubmissions that arrived while the original match was still running,
+    /// with their correlation ids
+    duplicates: Vec<(String, Option<String>)>,
+    /// When the key was first seen
+    registered: Instant,
+}
+
 /// Game pool manager that handles multiple concurrent games
 pub struct GamePool {
     queue_client: QueueClient,
     message_tx: mpsc::Sender<GamePoolMessage>,
     message_rx: mpsc::Receiver<GamePoolMessage>,
+    active_games: HashMap<String, ActiveGame>,
+    /// Games waiting for a free slot, highest priority first
+    pending_games: BinaryHeap<PendingGame>,
+    /// Arrival counter for pending games
+    next_sequence: u64,
+    workers: WorkerPool,
+    /// Stream of every observed game state, when streaming is enabled
+    states: Option<broadcast::Sender<StateUpdate>>,
+    /// GameComplete messages handed to the outbox for publishing
+    completions: mpsc::UnboundedSender<OutgoingCompletion>,
+    /// Publishes completions on a task of its own, taken when the pool runs
+    outbox: Option<CompletionOutbox>,
+    /// Where running matches are checkpointed, to recover them after a crash
+    store: Option<Arc<dyn MatchStore>>,
+    /// How often running matches are checkpointed
+    checkpoint_interval: Duration,
+    /// How much turn history games attach to their result, none when unset
+    history: Option<HistoryRetention>,
+    /// Idempotency keys of matches that have not finished yet
+    idempotency_inflight: HashMap<String, IdempotencyRecord>,
+    /// Idempotency keys of finished matches, with the match that was played for each
+    idempotency_keys: TtlCache<String, String>,
+    /// Ids of recently finished matches, to recognise redelivered GameStarting messages
+    recent_matches: TtlCache<String, ()>,
+    /// GameComplete messages of recently completed matches, served by the result API
+    results: TtlCache<String, Value>,
+    /// Maximum number of games running at once, 0 for unlimited
+    max_concurrent: usize,
+    /// Seed the seeds of matches without one are derived from, random seeds when unset
+    master_seed: Option<u64>,
+    /// Wall clock limit after which a running game is cancelled
+    game_timeout: Option<Duration>,
+    /// Submissions of a match allowed before a start failure is final
+    max_start_attempts: u32,
+    /// Set once draining, new games are refused from then on
+    drain_deadline: Option<Instant>,
+    /// Cleared while an operator has paused starting games
+    accepting: bool,
+    /// Whether a GameStarted event is published as each game begins running
+    publish_started: bool,
+    include_timing: bool,
 }
 
 impl GamePool {
-    /// Create a new game pool
-    pub fn new(queue_client: QueueClient) -> Self {
+    /// Create a new game pool, along with the worker threads running its games,
+    /// checkpointing matches to the configured directory
+    pub fn new(queue_client: QueueClient, config: &Config) -> Result<Self, PoolError> {
+        let pool = Self::with_runner(queue_client, config, Arc::new(EngineRunner))?;
+        Ok(match &config.match_store_dir {
+            Some(dir) => pool.with_store(Arc::new(
+                FileMatchStore::new(dir).map_err(PoolError::Store)?,
+            )),
+            None => pool,
+        })
+    }
+
+    /// Create a game pool whose workers create games with `runner`,
+    /// such as `ScriptedRunner` to test the pool without the engine.
+    /// Matches are only checkpointed once a store is given with `with_store`.
+    pub fn with_runner(
+        queue_client: QueueClient,
+        config: &Config,
+        runner: Arc<dyn GameRunner>,
+    ) -> Result<Self, PoolError> {
         let (message_tx, message_rx) = mpsc::channel(100);
+        let (completions, outgoing) = mpsc::unbounded_channel();
+        let outbox = CompletionOutbox {
+            queue_client: queue_client.clone(),
+            spill: config
+                .completion_spill_dir
+                .as_deref()
+                .map(CompletionSpill::new)
+                .transpose()
+                .map_err(PoolError::Spill)?,
+            completions: outgoing,
+            broker_down: false,
+        };
 
-        Self {
+        let worker_threads = match config.worker_threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads,
+        };
+
+        Ok(Self {
             queue_client,
             message_tx,
             message_rx,
-        }
+            active_games: HashMap::new(),
+            pending_games: BinaryHeap::new(),
+            next_sequence: 0,
+            workers: WorkerPool::new(worker_threads, runner)?,
+            states: config
+                .stream_states
+                .then(|| broadcast::channel(STATE_STREAM_CAPACITY).0),
+            completions,
+            outbox: Some(outbox),
+            store: None,
+            checkpoint_interval: Duration::from_secs(config.checkpoint_interval_secs.max(1)),
+            history: config.record_history.then_some(match config.history_limit {
+                0 => HistoryRetention::All,
+                limit => HistoryRetention::Latest(limit),
+            }),
+            idempotency_inflight: HashMap::new(),
+            idempotency_keys: TtlCache::new(IDEMPOTENCY_TTL, IDEMPOTENCY_CAPACITY),
+            recent_matches: TtlCache::new(RECENT_MATCH_TTL, RECENT_MATCH_CAPACITY),
+            results: TtlCache::new(
+                Duration::from_secs(config.result_cache_ttl_secs),
+                config.result_cache_capacity,
+            ),
+            max_concurrent: config.max_concurrent,
+            master_seed: config.master_seed,
+            game_timeout: (config.game_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.game_timeout_secs)),
+            max_start_attempts: config.max_start_attempts,
+            drain_deadline: None,
+            accepting: true,
+            publish_started: config.publish_started,
+            include_timing: config.include_timing,
+        })
+    }
+
+    /// Checkpoint running matches to `store`, and recover the matches
+    /// it holds from a previous run when the pool starts
+    pub fn with_store(mut self, store: Arc<dyn MatchStore>) -> Self {
+        self.store = Some(store);
+        self
     }
 
     /// Get a sender for sending messages to the game pool
@@ -60,187 +607,1981 @@ impl GamePool {
         self.message_tx.clone()
     }
 
+    /// Subscribe to the state of every game as it is played, if streaming is enabled
+    pub fn subscribe_states(&self) -> Option<broadcast::Receiver<StateUpdate>> {
+        self.states.as_ref().map(broadcast::Sender::subscribe)
+    }
+
     /// Start the game pool manager
-    pub async fn run(mut self) -> Result<()> {
+    pub async fn run(mut self) -> Result<(), PoolError> {
         info!("Starting game pool manager");
 
-        let mut active_games: HashMap<String, JoinHandle<()>> = HashMap::new();
+        if let Some(states) = self.subscribe_states() {
+            tokio::spawn(Self::publish_states(self.queue_client.clone(), states));
+        }
+        let outbox = self.outbox.take().map(|outbox| tokio::spawn(outbox.run()));
+
+        let mut timeout_sweep = tokio::time::interval(TIMEOUT_SWEEP_INTERVAL);
+        let mut checkpoint_sweep = tokio::time::interval(self.checkpoint_interval);
+
+        self.recover_orphans().await;
+
+        loop {
+            if let Some(deadline) = self.drain_deadline {
+                if self.active_games.is_empty() {
+                    info!("All games drained");
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    warn!(
+                        "Drain deadline reached with {} games still active",
+                        self.active_games.len()
+                    );
+                    self.abort_all().await;
+                    break;
+                }
+            }
+
+            let message = tokio::select! {
+                message = self.message_rx.recv() => message,
+                _ = timeout_sweep.tick() => {
+                    self.expire_games().await;
+                    self.expire_idempotency();
+                    continue;
+                }
+                _ = checkpoint_sweep.tick() => {
+                    self.checkpoint_active().await;
+                    continue;
+                }
+            };
+            let Some(message) = message else {
+                break;
+            };
 
-        while let Some(message) = self.message_rx.recv().await {
             match message {
-                GamePoolMessage::StartGame { match_id, players } => {
-                    match self.start_game(match_id.clone(), players).await {
-                        Ok(handle) => {
-                            active_games.insert(match_id, handle);
-                        }
+                GamePoolMessage::StartGame {
+                    request,
+                    routing_key,
+                    correlation_id,
+                    enqueued_at,
+                } => {
+                    if self.drain_deadline.is_some() {
+                        warn!(
+                            "Game pool is draining, handing match {} back to the queue",
+                            request.match_id
+                        );
+                        self.hand_back(request, &routing_key, correlation_id).await;
+                        continue;
+                    }
+
+                    let StartGameRequest {
+                        match_id,
+                        players,
+                        fill_bots,
+                        idempotency_key,
+                        seed,
+                        ruleset,
+                        priority,
+                        attempt,
+                    } = request;
+
+                    if self.is_known_match(&match_id) {
+                        warn!(
+                            "Ignoring GameStarting for match {}, it is already running or finished",
+                            match_id
+                        );
+                        continue;
+                    }
+
+                    let fill_bots = match fill_bots
+                        .iter()
+                        .map(|name| name.parse::<BotStrategy>())
+                        .collect::<Result<Vec<_>, _>>()
+                    {
+                        Ok(fill_bots) => fill_bots,
                         Err(e) => {
-                            error!("Failed to start game {}: {}", match_id, e);
+                            self.reject_game(&match_id, correlation_id, &e.to_string());
+                            continue;
                         }
+                    };
+                    if let Err(e) = controllers::validate_players(&players, &fill_bots) {
+                        self.reject_game(&match_id, correlation_id, &e.to_string());
+                        continue;
                     }
+                    let ruleset = ruleset.unwrap_or_default();
+
+                    if let Some(key) = &idempotency_key {
+                        // A retry still owns the key its first attempt registered
+                        let retrying = attempt > 1
+                            && self
+                                .idempotency_inflight
+                                .get(key)
+                                .is_some_and(|record| record.match_id == match_id);
+                        if retrying {
+                            info!("Retrying match {}, attempt {}", match_id, attempt);
+                        } else if self.handle_duplicate(key, &match_id, correlation_id.as_deref()) {
+                            continue;
+                        } else {
+                            self.idempotency_inflight.insert(
+                                key.clone(),
+                                IdempotencyRecord {
+                                    match_id: match_id.clone(),
+                                    duplicates: Vec::new(),
+                                    registered: Instant::now(),
+                                },
+                            );
+                        }
+                    }
+
+                    if !self.accepting {
+                        info!("Game starts are paused, holding match {}", match_id);
+                    } else if !self.has_capacity() {
+                        info!(
+                            "Game pool at capacity ({} active), queuing match {}",
+                            self.active_games.len(),
+                            match_id
+                        );
+                    }
+                    self.next_sequence += 1;
+                    self.pending_games.push(PendingGame {
+                        match_id,
+                        players,
+                        fill_bots,
+                        idempotency_key,
+                        seed,
+                        ruleset,
+                        priority,
+                        sequence: self.next_sequence,
+                        attempt,
+                        routing_key,
+                        correlation_id,
+                        enqueued_at,
+                    });
+                    self.dispatch_pending().await;
                 }
-                GamePoolMessage::GameComplete { match_id } => {
+                GamePoolMessage::GameComplete {
+                    match_id,
+                    result,
+                    finished_at,
+                } => {
                     info!("Game {} completed successfully", match_id);
-                    if let Err(e) = self.handle_game_completion(&match_id).await {
-                        error!("Error handling game completion for {}: {}", match_id, e);
-                    }
-                    active_games.remove(&match_id); // Task is done, just remove handle
+                    self.finish_game(&match_id, GameEnding::Finished(result), finished_at)
+                        .await;
+                    self.dispatch_pending().await;
                 }
-                GamePoolMessage::GameError { match_id, error } => {
+                GamePoolMessage::GameStartFailed { match_id, error } => {
+                    error!("Game {} failed to start: {}", match_id, error);
+                    self.retry_start(&match_id, &error).await;
+                    self.dispatch_pending().await;
+                }
+                GamePoolMessage::GameError {
+                    match_id,
+                    error,
+                    finished_at,
+                } => {
                     error!("Game {} ended with an error: {}", match_id, error);
-                    if let Err(e) = self.handle_game_completion(&match_id).await {
-                        error!("Error handling game completion for {}: {}", match_id, e);
-                    }
-                    active_games.remove(&match_id);
+                    self.finish_game(&match_id, GameEnding::Failed(error), finished_at)
+                        .await;
+                    self.dispatch_pending().await;
+                }
+                GamePoolMessage::Drain { deadline } => {
+                    info!(
+                        "Draining game pool with {} games active",
+                        self.active_games.len()
+                    );
+                    self.hand_back_pending().await;
+                    self.drain_deadline = Some(deadline);
                 }
                 GamePoolMessage::Shutdown => {
-                    info!("Shutting down game pool");
-                    for (match_id, handle) in active_games.drain() {
-                        info!("Aborting game: {}", match_id);
-                        handle.abort();
-                    }
+                    info!(
+                        "Shutting down game pool, aborting {} active and {} pending games",
+                        self.active_games.len(),
+                        self.pending_games.len()
+                    );
+                    self.abort_all().await;
                     break;
                 }
+                GamePoolMessage::QueryActive { respond_to } => {
+                    let active = self
+                        .active_games
+                        .iter()
+                        .map(|(match_id, game)| MatchInfo {
+                            match_id: match_id.clone(),
+                            players: game.players.clone(),
+                            started_at: game.start_time,
+                        })
+                        .collect();
+                    let mut pending = BTreeMap::new();
+                    for game in &self.pending_games {
+                        *pending.entry(game.priority).or_insert(0) += 1;
+                    }
+                    let mut waiting: Vec<&PendingGame> = self.pending_games.iter().collect();
+                    waiting.sort_by(|a, b| b.cmp(a));
+                    let pending_matches = waiting
+                        .into_iter()
+                        .map(|game| PendingMatchInfo {
+                            match_id: game.match_id.clone(),
+                            players: game.players.clone(),
+                            priority: game.priority,
+                        })
+                        .collect();
+                    // The requester may have given up waiting, which is fine
+                    let _ = respond_to.send(PoolStatus {
+                        accepting: self.accepting,
+                        max_concurrent: self.max_concurrent,
+                        active_count: self.active_games.len(),
+                        active,
+                        pending_count: self.pending_games.len(),
+                        pending,
+                        pending_matches,
+                    });
+                }
+                GamePoolMessage::CancelGame {
+                    match_id,
+                    respond_to,
+                } => {
+                    let cancelled = self.cancel_game(&match_id).await;
+                    let _ = respond_to.send(cancelled);
+                    self.dispatch_pending().await;
+                }
+                GamePoolMessage::SetAccepting(accepting) => {
+                    if accepting != self.accepting {
+                        if accepting {
+                            info!(
+                                "Resuming game starts with {} games pending",
+                                self.pending_games.len()
+                            );
+                        } else {
+                            warn!("Pausing game starts, new matches are held until resumed");
+                        }
+                    }
+                    self.accepting = accepting;
+                    self.dispatch_pending().await;
+                }
+                GamePoolMessage::SetMaxConcurrent(max_concurrent) => {
+                    info!(
+                        "Changing max concurrent games from {} to {} ({} active, {} pending)",
+                        self.max_concurrent,
+                        max_concurrent,
+                        self.active_games.len(),
+                        self.pending_games.len()
+                    );
+                    self.max_concurrent = max_concurrent;
+                    self.dispatch_pending().await;
+                }
+                GamePoolMessage::QueryResult {
+                    match_id,
+                    respond_to,
+                } => {
+                    let result = self.results.get_mut(&match_id).cloned();
+                    let _ = respond_to.send(result);
+                }
             }
         }
 
+        // Closing the completion channel lets the outbox finish publishing and exit
+        drop(self);
+        if let Some(outbox) = outbox {
+            if let Err(e) = outbox.await {
+                error!("Completion outbox failed: {}", e);
+            }
+        }
         info!("Game pool shut down");
         Ok(())
     }
 
-    /// Start a new game in a background blocking task
-    async fn start_game(&self, match_id: String, players: Vec<String>) -> Result<JoinHandle<()>> {
-        info!(
-            "Starting new game: {} with players: {:?}",
-            match_id, players
+    /// Hand every game that has not started yet back to the queue,
+    /// highest priority first, for another instance to play
+    async fn hand_back_pending(&mut self) {
+        let pending = std::mem::take(&mut self.pending_games).into_sorted_vec();
+        metrics::set_games(self.active_games.len(), 0);
+        if pending.is_empty() {
+            return;
+        }
+
+        warn!(
+            "Handing {} games that never started back to the queue",
+            pending.len()
         );
+        for game in pending.into_iter().rev() {
+            let routing_key = game.routing_key.clone();
+            let correlation_id = game.correlation_id.clone();
+            self.hand_back(game.into_request(), &routing_key, correlation_id)
+                .await;
+        }
+    }
 
-        let controllers: Vec<GameController> = (0..4)
-            .map(|i| {
-                let player_name = players
-                    .get(i)
-                    .cloned()
-                    .unwrap_or_else(|| "AngryDiscardoBot".to_string());
-                GameController::Embedded(player_name)
-            })
-            .collect();
+    /// Republish a match this pool will not play to the incoming queue under
+    /// its original routing key. Its GameStarting delivery was acknowledged,
+    /// so it would be lost otherwise. A match that cannot be republished is
+    /// rejected, and resubmissions waiting on it are rejected along with it.
+    #[instrument(skip_all, fields(match_id = %request.match_id))]
+    async fn hand_back(
+        &mut self,
+        request: StartGameRequest,
+        routing_key: &str,
+        correlation_id: Option<String>,
+    ) {
+        const REASON: &str = "Game pool is shutting down";
 
-        // Channel for the sync task to report its final status
-        let (status_tx, mut status_rx) = mpsc::channel(1);
+        let match_id = request.match_id.clone();
+        let idempotency_key = request.idempotency_key.clone();
+        let published = match serde_json::to_vec(&request) {
+            Ok(data) => self
+                .queue_client
+                .publish_game_starting(
+                    routing_key,
+                    &data,
+                    Encoding::Json,
+                    correlation_id.as_deref(),
+                )
+                .await
+                .map_err(PoolError::from),
+            Err(e) => Err(e.into()),
+        };
+        match published {
+            Ok(()) => info!("Handed match {} back to the queue", match_id),
+            Err(e) => {
+                error!("Failed to hand match {} back to the queue: {}", match_id, e);
+                self.reject_game(&match_id, correlation_id, REASON);
+            }
+        }
 
-        // Spawn the entire game loop in a dedicated blocking thread
-        // to avoid blocking the async runtime.
-        let match_id_clone_blocking = match_id.clone();
-        let handle = spawn_blocking(move || {
-            Self::run_game_sync(match_id_clone_blocking, controllers, status_tx);
-        });
+        // Whoever plays the match now answers later resubmissions,
+        // the ones already waiting here would never be
+        let Some(key) = idempotency_key else {
+            return;
+        };
+        let owned = self
+            .idempotency_inflight
+            .get(&key)
+            .is_some_and(|record| record.match_id == match_id);
+        if !owned {
+            return;
+        }
+        if let Some(record) = self.idempotency_inflight.remove(&key) {
+            for (duplicate, correlation_id) in record.duplicates {
+                self.reject_game(&duplicate, correlation_id, REASON);
+            }
+        }
+    }
 
-        // Spawn an async task to bridge the result from the blocking
-        // task back to the main game pool's message loop.
-        let pool_sender = self.message_tx.clone();
-        tokio::spawn(async move {
-            if let Some(status) = status_rx.recv().await {
-                let msg = match status {
-                    GameStatus::Finished => GamePoolMessage::GameComplete {
-                        match_id: match_id.clone(),
-                    },
-                    GameStatus::Error(e) => GamePoolMessage::GameError {
-                        match_id: match_id.clone(),
-                        error: e,
-                    },
-                };
-                if let Err(e) = pool_sender.send(msg).await {
-                    error!("Failed to send game result to pool for {}: {}", match_id, e);
-                }
+    /// Abort every running game, and hand the games that have not started
+    /// back to the queue. Checkpointed games are left for the next start to
+    /// recover, the others are answered with a cancelled completion.
+    async fn abort_all(&mut self) {
+        self.hand_back_pending().await;
+        let checkpointed = self.store.is_some();
+        let aborted: Vec<String> = self.active_games.keys().cloned().collect();
+        for match_id in aborted {
+            info!("Aborting game: {}", match_id);
+            if let Some(game) = self.active_games.get(&match_id) {
+                game.stop();
             }
-        });
+            if checkpointed {
+                self.active_games.remove(&match_id);
+            } else {
+                self.finish_game(&match_id, GameEnding::Cancelled, Instant::now())
+                    .await;
+            }
+        }
+        metrics::set_games(0, 0);
+    }
 
-        Ok(handle)
+    /// Whether a match id is pending, running or recently finished, as it
+    /// would be when the broker redelivers its GameStarting message
+    fn is_known_match(&mut self, match_id: &str) -> bool {
+        self.holds_match(match_id) || self.recent_matches.contains_key(match_id)
     }
 
-    /// Run game logic in a blocking thread
-    fn run_game_sync(
-        match_id: String,
-        controllers: Vec<GameController>,
-        status_tx: mpsc::Sender<GameStatus>,
-    ) {
-        info!("Sync game runner starting for match: {}", match_id);
+    /// Whether a match is pending or running in the pool
+    fn holds_match(&self, match_id: &str) -> bool {
+        self.active_games.contains_key(match_id)
+            || self
+                .pending_games
+                .iter()
+                .any(|game| game.match_id == match_id)
+    }
 
-        let mut game_match = match GameMatch::try_new(match_id.clone(), controllers) {
-            Ok(game) => game,
-            Err(e) => {
-                error!("Failed to create game match {}: {}", match_id, e);
-                let _ = status_tx.blocking_send(GameStatus::Error(e.to_string()));
-                return;
-            }
-        };
+    /// Forget the idempotency keys of matches that left the pool without
+    /// finishing here longer than `IDEMPOTENCY_TTL` ago, such as a retry
+    /// resubmitted to the queue that another instance picked up
+    fn expire_idempotency(&mut self) {
+        let stale: Vec<String> = self
+            .idempotency_inflight
+            .iter()
+            .filter(|(_, record)| {
+                record.registered.elapsed() > IDEMPOTENCY_TTL && !self.holds_match(&record.match_id)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            debug!(
+                "Forgetting idempotency key '{}' of a match that left the pool",
+                key
+            );
+            self.idempotency_inflight.remove(&key);
+        }
+    }
 
-        // Autonomous game loop that runs to completion
-        let mut total_rounds = 0;
+    /// Whether another game may start without exceeding `max_concurrent`
+    fn has_capacity(&self) -> bool {
+        self.max_concurrent == 0 || self.active_games.len() < self.max_concurrent
+    }
 
-        let final_status = loop {
-            match game_match.advance() {
-                Ok(true) => {
-                    // Game continues.
-                    // Eventually advance will have a lot more to do with network waits
-                    // where we probably wont need this sleep to prevent the CPU from
-                    // getting pinned.
-                    let observed = game_match.observe_state();
-                    total_rounds += 1;
-                    if total_rounds.rem(10) == 0 {
-                        info!(
-                            "Game {} advanced {} rounds. Current state: {:?}",
-                            match_id, total_rounds, observed
-                        );
-                    }
-                    if let Some(observed) = observed {
-                        if observed.current_state() == StateFunctionType::GameEnd {
-                            break GameStatus::Finished;
-                        }
+    /// Start pending games, highest priority first and in arrival order
+    /// within a priority, while there are free slots and starts are not paused
+    async fn dispatch_pending(&mut self) {
+        while self.accepting && self.has_capacity() {
+            let Some(game) = self.pending_games.pop() else {
+                break;
+            };
+
+            let seed = game.seed.unwrap_or_else(|| match self.master_seed {
+                Some(master_seed) => derive_seed(master_seed, &game.match_id),
+                None => rand::thread_rng().gen(),
+            });
+            let mut active = ActiveGame {
+                cancelled: Arc::new(AtomicBool::new(false)),
+                steps: Arc::new(AtomicU64::new(0)),
+                players: game.players,
+                fill_bots: game.fill_bots,
+                idempotency_key: game.idempotency_key,
+                seed,
+                ruleset: game.ruleset,
+                priority: game.priority,
+                attempt: game.attempt,
+                routing_key: game.routing_key,
+                correlation_id: game.correlation_id,
+                enqueued_at: game.enqueued_at,
+                started_at: Instant::now(),
+                start_time: Utc::now(),
+                waker: None,
+            };
+            match self.start_game(game.match_id.clone(), &active).await {
+                Ok(waker) => {
+                    active.waker = Some(waker);
+                    metrics::game_started();
+                    if self.publish_started {
+                        self.announce_start(&game.match_id, &active).await;
                     }
-                    std::thread::sleep(std::time::Duration::from_millis(1));
-                }
-                Ok(false) => {
-                    info!("Game {} finished.", match_id);
-                    break GameStatus::Finished;
+                    self.save_checkpoint(&game.match_id, &active).await;
+                    self.active_games.insert(game.match_id, active);
                 }
                 Err(e) => {
-                    error!("Game {} failed to advance: {}", match_id, e);
-                    break GameStatus::Error(e.to_string());
+                    error!("Failed to start game {}: {}", game.match_id, e);
+                    metrics::game_errored(None);
+                    self.recent_matches.insert(game.match_id.clone(), ());
+                    let details = CompletionDetails {
+                        seed: Some(active.seed),
+                        ruleset: Some(active.ruleset),
+                        correlation_id: active.correlation_id,
+                        error: Some(format!("Failed to start: {}", e)),
+                        ..Default::default()
+                    };
+                    self.handle_game_completion(&game.match_id, &details);
+                    self.answer_duplicates(&game.match_id, active.idempotency_key);
                 }
             }
-        };
+        }
+        metrics::set_games(self.active_games.len(), self.pending_games.len());
+    }
 
-        if let Err(e) = status_tx.blocking_send(final_status) {
+    /// Publish a GameStarted event for a game that just began running.
+    /// The event is informational, so a failure to publish it is only logged.
+    async fn announce_start(&self, match_id: &str, game: &ActiveGame) {
+        let message = json!({
+            "schema_version": SCHEMA_VERSION,
+            "match_id": match_id,
+            "players": game.players,
+            "seed": game.seed,
+            "ruleset": game.ruleset.name(),
+            "started_at": game.start_time.to_rfc3339(),
+        });
+        let result = match serde_json::to_vec(&message) {
+            Ok(data) => {
+                self.queue_client
+                    .publish_game_started(match_id, &data, game.correlation_id.as_deref())
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
             warn!(
-                "Could not send final status for game {}: receiver dropped. {}",
+                "Failed to publish game started event for {}: {}",
                 match_id, e
             );
         }
+    }
+
+    /// Cancel games that have been running for longer than `game_timeout`.
+    /// Their completion is handled here rather than waiting on the worker,
+    /// since a worker stuck inside the engine may never report back.
+    async fn expire_games(&mut self) {
+        let Some(timeout) = self.game_timeout else {
+            return;
+        };
+
+        let expired: Vec<String> = self
+            .active_games
+            .iter()
+            .filter(|(_, game)| game.started_at.elapsed() > timeout)
+            .map(|(match_id, _)| match_id.clone())
+            .collect();
+
+        for match_id in expired {
+            if let Some(game) = self.active_games.get(&match_id) {
+                game.stop();
+            }
+            error!("Game {} timed out after {:?}", match_id, timeout);
+            self.finish_game(&match_id, GameEnding::TimedOut(timeout), Instant::now())
+                .await;
+        }
 
-        info!("Sync game runner finished for match: {}", match_id);
+        self.dispatch_pending().await;
     }
 
-    /// Handle game completion (publish to queue, etc.)
-    async fn handle_game_completion(&self, match_id: &str) -> Result<()> {
-        info!("Publishing completion event for game: {}", match_id);
-        let game_complete_data = Self::create_game_complete_message(match_id).await?;
+    /// Stop a running game and publish its cancellation, returning whether it was running
+    #[instrument(skip_all, fields(match_id = %match_id))]
+    async fn cancel_game(&mut self, match_id: &str) -> Option<MatchPhase> {
+        let Some(game) = self.active_games.get(match_id) else {
+            return self.cancel_pending(match_id).then_some(MatchPhase::Pending);
+        };
+
+        warn!("Cancelling game {} on operator request", match_id);
+        game.stop();
+        self.finish_game(match_id, GameEnding::Cancelled, Instant::now())
+            .await;
+        Some(MatchPhase::Active)
+    }
+
+    /// Remove a match from the pending queue before it starts, answering it
+    /// with a cancelled completion event. False if it is not pending.
+    fn cancel_pending(&mut self, match_id: &str) -> bool {
+        let (cancelled, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending_games)
+            .into_vec()
+            .into_iter()
+            .partition(|game| game.match_id == match_id);
+        self.pending_games = BinaryHeap::from(kept);
+        let Some(game) = cancelled.into_iter().next() else {
+            return false;
+        };
+
+        warn!("Cancelling pending game {} on operator request", match_id);
+        metrics::game_errored(None);
+        metrics::set_games(self.active_games.len(), self.pending_games.len());
+
+        let details = CompletionDetails {
+            cancelled: true,
+            seed: game.seed,
+            ruleset: Some(game.ruleset),
+            correlation_id: game.correlation_id,
+            ..Default::default()
+        };
+        self.handle_game_completion(match_id, &details);
+        self.answer_duplicates(match_id, game.idempotency_key);
+        true
+    }
+
+    /// Resubmit a game that failed to start to the incoming queue, unless it
+    /// has used up its attempts, in which case it finishes as an error
+    #[instrument(skip_all, fields(match_id = %match_id))]
+    async fn retry_start(&mut self, match_id: &str, error: &str) {
+        let Some(game) = self.active_games.get(match_id) else {
+            debug!("Ignoring start failure of inactive game {}", match_id);
+            return;
+        };
+
+        if game.attempt >= self.max_start_attempts {
+            error!(
+                "Game {} failed to start after {} attempts, giving up",
+                match_id, game.attempt
+            );
+            let error = format!("Failed to start after {} attempts: {}", game.attempt, error);
+            self.finish_game(match_id, GameEnding::Failed(error), Instant::now())
+                .await;
+            return;
+        }
+
+        let data = match Self::create_retry_message(&Self::checkpoint(match_id, game)) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to create retry message for {}: {}", match_id, e);
+                let error = format!("Failed to start: {}", error);
+                self.finish_game(match_id, GameEnding::Failed(error), Instant::now())
+                    .await;
+                return;
+            }
+        };
         if let Err(e) = self
             .queue_client
-            .publish_game_complete(match_id, &game_complete_data)
+            .publish_game_starting(
+                &game.routing_key,
+                &data,
+                Encoding::Json,
+                game.correlation_id.as_deref(),
+            )
             .await
         {
-            error!("Failed to publish game complete event: {}", e);
-            return Err(e.into());
+            error!("Failed to resubmit game {}: {}", match_id, e);
+            let error = format!("Failed to start: {}", error);
+            self.finish_game(match_id, GameEnding::Failed(error), Instant::now())
+                .await;
+            return;
         }
-        Ok(())
+
+        info!(
+            "Resubmitted game {} for attempt {}",
+            match_id,
+            game.attempt + 1
+        );
+        self.active_games.remove(match_id);
+        self.remove_checkpoint(match_id).await;
+        metrics::game_errored(None);
     }
 
-    /// Create a GameComplete message
-    async fn create_game_complete_message(match_id: &str) -> Result<Vec<u8>> {
-        let message = json!({
-            "match_id": match_id,
-            "status": "completed"
-        });
-        Ok(serde_json::to_vec(&message)?)
+    /// Answer a match that will not be played with a rejected completion event
+    #[instrument(skip_all, fields(match_id = %match_id))]
+    fn reject_game(&mut self, match_id: &str, correlation_id: Option<String>, reason: &str) {
+        error!("Rejecting match {}: {}", match_id, reason);
+        metrics::game_errored(None);
+
+        let details = CompletionDetails {
+            rejected: Some(reason.to_string()),
+            correlation_id,
+            ..Default::default()
+        };
+        self.handle_game_completion(match_id, &details);
+    }
+
+    /// Check whether a submission reuses the idempotency key of an earlier match.
+    /// Duplicates of a finished match are answered immediately with its result,
+    /// duplicates of a running match are answered once it finishes.
+    #[instrument(skip_all, fields(match_id = %match_id))]
+    fn handle_duplicate(
+        &mut self,
+        key: &str,
+        match_id: &str,
+        correlation_id: Option<&str>,
+    ) -> bool {
+        if let Some(record) = self.idempotency_inflight.get_mut(key) {
+            warn!(
+                "Match {} reuses idempotency key '{}' of running match {}, answering it once that finishes",
+                match_id, key, record.match_id
+            );
+            record
+                .duplicates
+                .push((match_id.to_string(), correlation_id.map(str::to_string)));
+            return true;
+        }
+
+        let Some(original) = self.idempotency_keys.get_mut(key).cloned() else {
+            return false;
+        };
+        warn!(
+            "Match {} reuses idempotency key '{}' of finished match {}, not starting a new game",
+            match_id, key, original
+        );
+        self.answer_duplicate(match_id, &original, correlation_id.map(str::to_string));
+        true
+    }
+
+    /// Publish completion for a finished game and any duplicate submissions of it
+    #[instrument(skip_all, fields(match_id = %match_id))]
+    async fn finish_game(&mut self, match_id: &str, ending: GameEnding, finished_at: Instant) {
+        // Task is done, just remove handle
+        let Some(game) = self.active_games.remove(match_id) else {
+            // Already finished, e.g. a timed out runner reporting back late
+            debug!("Ignoring completion of inactive game {}", match_id);
+            return;
+        };
+        self.recent_matches.insert(match_id.to_string(), ());
+        self.remove_checkpoint(match_id).await;
+
+        let run = finished_at.duration_since(game.started_at);
+        let mut details = CompletionDetails {
+            seed: Some(game.seed),
+            ruleset: Some(game.ruleset),
+            correlation_id: game.correlation_id,
+            ..Default::default()
+        };
+        match ending {
+            GameEnding::Finished(result) => {
+                metrics::game_completed(run);
+                details.result = Some(result);
+            }
+            GameEnding::Cancelled => {
+                metrics::game_errored(Some(run));
+                details.cancelled = true;
+            }
+            GameEnding::TimedOut(timeout) => {
+                metrics::game_errored(Some(run));
+                details.timed_out = true;
+                details.error = Some(format!("Game timed out after {:?}", timeout));
+            }
+            GameEnding::Failed(error) => {
+                metrics::game_errored(Some(run));
+                details.error = Some(error);
+            }
+        }
+        if self.include_timing {
+            details.timing = Some(GameTiming {
+                queue_wait: game.started_at.duration_since(game.enqueued_at),
+                run,
+                finished_at,
+            });
+        }
+
+        self.handle_game_completion(match_id, &details);
+        self.answer_duplicates(match_id, game.idempotency_key);
+    }
+
+    /// Once a match submitted under `idempotency_key` has finished, remember
+    /// the key as finished and answer the resubmissions that were waiting on it
+    fn answer_duplicates(&mut self, match_id: &str, idempotency_key: Option<String>) {
+        let Some(key) = idempotency_key else {
+            return;
+        };
+        let Some(record) = self.idempotency_inflight.remove(&key) else {
+            return;
+        };
+
+        self.idempotency_keys.insert(key, match_id.to_string());
+        for (duplicate, correlation_id) in record.duplicates {
+            self.answer_duplicate(&duplicate, match_id, correlation_id);
+        }
+    }
+
+    /// Answer a resubmission of the finished match `original` with the
+    /// GameComplete message of `original`, under the resubmission's match id
+    /// and correlation id. Only `duplicate_of` is sent once the result of
+    /// `original` is no longer cached.
+    fn answer_duplicate(
+        &mut self,
+        duplicate: &str,
+        original: &str,
+        correlation_id: Option<String>,
+    ) {
+        let message = match self.results.get_mut(original) {
+            Some(result) => {
+                let mut message = result.clone();
+                message["match_id"] = json!(duplicate);
+                message["duplicate_of"] = json!(original);
+                match &correlation_id {
+                    Some(correlation_id) => message["correlation_id"] = json!(correlation_id),
+                    None => {
+                        if let Some(fields) = message.as_object_mut() {
+                            fields.remove("correlation_id");
+                        }
+                    }
+                }
+                message
+            }
+            None => Self::create_game_complete_message(
+                duplicate,
+                &CompletionDetails {
+                    duplicate_of: Some(original.to_string()),
+                    correlation_id: correlation_id.clone(),
+                    ..Default::default()
+                },
+            ),
+        };
+        self.publish_completion(duplicate, message, correlation_id, None);
+    }
+
+    /// Start a new game on the worker pool, returning the waker of its worker.
+    /// The game runs in a span carrying its match id, so the worker's log
+    /// lines for it can be picked out.
+    #[instrument(skip_all, fields(match_id = %match_id))]
+    async fn start_game(&self, match_id: String, game: &ActiveGame) -> Result<Waker, PoolError> {
+        let players = &game.players;
+        info!(
+            "Starting new game: {} with players: {:?}, seed {} and {} rules",
+            match_id,
+            players,
+            game.seed,
+            game.ruleset.name()
+        );
+
+        let controllers = controllers::seat_players(players, &game.fill_bots);
+        if players.len() < controllers.len() {
+            info!(
+                "Match {} has {} players, seating bots {:?} in the remaining seats",
+                match_id,
+                players.len(),
+                controllers[players.len()..]
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        // Channel for the worker to report the game's final status
+        let (status_tx, mut status_rx) = mpsc::channel(1);
+
+        let waker = self.workers.submit(GameJob {
+            match_id: match_id.clone(),
+            controllers,
+            seed: game.seed,
+            ruleset: game.ruleset,
+            cancelled: game.cancelled.clone(),
+            steps: game.steps.clone(),
+            status_tx,
+            states: self.states.clone(),
+            history: self.history,
+            span: Span::current(),
+        })?;
+
+        // Spawn an async task to bridge the result from the worker
+        // thread back to the main game pool's message loop.
+        let pool_sender = self.message_tx.clone();
+        tokio::spawn(
+            async move {
+                if let Some(status) = status_rx.recv().await {
+                    let msg = match status {
+                        GameStatus::Finished(result) => GamePoolMessage::GameComplete {
+                            match_id: match_id.clone(),
+                            result,
+                            finished_at: Instant::now(),
+                        },
+                        GameStatus::StartFailed(e) => GamePoolMessage::GameStartFailed {
+                            match_id: match_id.clone(),
+                            error: e,
+                        },
+                        GameStatus::Error(e) => GamePoolMessage::GameError {
+                            match_id: match_id.clone(),
+                            error: e,
+                            finished_at: Instant::now(),
+                        },
+                    };
+                    if let Err(e) = pool_sender.send(msg).await {
+                        error!("Failed to send game result to pool for {}: {}", match_id, e);
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(waker)
+    }
+
+    /// Forward streamed game states to the state topic until every game has stopped
+    async fn publish_states(
+        queue_client: QueueClient,
+        mut states: broadcast::Receiver<StateUpdate>,
+    ) {
+        loop {
+            match states.recv().await {
+                Ok(update) => {
+                    let data = match serde_json::to_vec(&update) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            warn!("Failed to encode game state: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = queue_client
+                        .publish_game_state(&update.match_id, &data)
+                        .await
+                    {
+                        warn!("Failed to stream game state: {}", e);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Game state stream fell behind, skipped {} states", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Handle game completion (publish to queue, etc.)
+    #[instrument(skip_all, fields(match_id = %match_id))]
+    fn handle_game_completion(&mut self, match_id: &str, details: &CompletionDetails) {
+        let message = Self::create_game_complete_message(match_id, details);
+        let finished_at = details.timing.as_ref().map(|timing| timing.finished_at);
+        self.publish_completion(
+            match_id,
+            message,
+            details.correlation_id.clone(),
+            finished_at,
+        );
+    }
+
+    /// Cache a GameComplete message for the result API and hand it to the
+    /// outbox to publish. With `finished_at`, each publish attempt stamps the
+    /// time since the game finished as `timing.publish_ms`.
+    fn publish_completion(
+        &mut self,
+        match_id: &str,
+        message: Value,
+        correlation_id: Option<String>,
+        finished_at: Option<Instant>,
+    ) {
+        // The history can be large, keep results small as many are held at once
+        let mut result = message.clone();
+        if let Some(result) = result.as_object_mut() {
+            result.remove("history");
+        }
+        self.results.insert(match_id.to_string(), result);
+
+        let completion = OutgoingCompletion {
+            match_id: match_id.to_string(),
+            message,
+            correlation_id,
+            finished_at,
+        };
+        if self.completions.send(completion).is_err() {
+            error!(
+                "Completion outbox has stopped, game complete event for {} was not published",
+                match_id
+            );
+        }
+    }
+
+    /// Checkpoint of a running game, enough to run it again from the start
+    fn checkpoint(match_id: &str, game: &ActiveGame) -> MatchCheckpoint {
+        MatchCheckpoint {
+            match_id: match_id.to_string(),
+            players: game.players.clone(),
+            fill_bots: game
+                .fill_bots
+                .iter()
+                .map(|bot| bot.name().to_string())
+                .collect(),
+            seed: game.seed,
+            ruleset: game.ruleset,
+            priority: game.priority,
+            attempt: game.attempt,
+            routing_key: game.routing_key.clone(),
+            idempotency_key: game.idempotency_key.clone(),
+            correlation_id: game.correlation_id.clone(),
+            steps: game.steps.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn save_checkpoint(&self, match_id: &str, game: &ActiveGame) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        if let Err(e) = store.save(&Self::checkpoint(match_id, game)).await {
+            warn!("Failed to checkpoint game {}: {}", match_id, e);
+        }
+    }
+
+    async fn remove_checkpoint(&self, match_id: &str) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        if let Err(e) = store.remove(match_id).await {
+            warn!("Failed to remove checkpoint of game {}: {}", match_id, e);
+        }
+    }
+
+    /// Refresh the checkpoint of every running game
+    async fn checkpoint_active(&self) {
+        for (match_id, game) in &self.active_games {
+            self.save_checkpoint(match_id, game).await;
+        }
+    }
+
+    /// Deal with matches a previous run checkpointed but never finished:
+    /// resubmit them to be played again from their seed while they have start
+    /// attempts left, or publish a cancelled completion for them otherwise
+    async fn recover_orphans(&mut self) {
+        let Some(store) = self.store.clone() else {
+            return;
+        };
+        let checkpoints = match store.load_all().await {
+            Ok(checkpoints) => checkpoints,
+            Err(e) => {
+                error!("Failed to read match checkpoints: {}", e);
+                return;
+            }
+        };
+
+        for checkpoint in checkpoints {
+            let match_id = checkpoint.match_id.as_str();
+            if checkpoint.attempt < self.max_start_attempts {
+                warn!(
+                    "Match {} was interrupted after {} steps, resubmitting it for attempt {}",
+                    match_id,
+                    checkpoint.steps,
+                    checkpoint.attempt + 1
+                );
+                let published = match Self::create_retry_message(&checkpoint) {
+                    Ok(data) => self
+                        .queue_client
+                        .publish_game_starting(
+                            &checkpoint.routing_key,
+                            &data,
+                            Encoding::Json,
+                            checkpoint.correlation_id.as_deref(),
+                        )
+                        .await
+                        .map_err(PoolError::from),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = published {
+                    // Keep the checkpoint for the next start to try again
+                    error!("Failed to resubmit interrupted match {}: {}", match_id, e);
+                    continue;
+                }
+            } else {
+                warn!(
+                    "Match {} was interrupted after {} steps on its last attempt, cancelling it",
+                    match_id, checkpoint.steps
+                );
+                let details = CompletionDetails {
+                    cancelled: true,
+                    seed: Some(checkpoint.seed),
+                    ruleset: Some(checkpoint.ruleset),
+                    correlation_id: checkpoint.correlation_id.clone(),
+                    ..Default::default()
+                };
+                self.handle_game_completion(match_id, &details);
+            }
+            if let Err(e) = store.remove(match_id).await {
+                warn!("Failed to remove checkpoint of game {}: {}", match_id, e);
+            }
+        }
+    }
+
+    /// Create a GameStarting message resubmitting a game for its next attempt
+    fn create_retry_message(game: &MatchCheckpoint) -> Result<Vec<u8>, PoolError> {
+        let request = StartGameRequest {
+            match_id: game.match_id.clone(),
+            players: game.players.clone(),
+            fill_bots: game.fill_bots.clone(),
+            idempotency_key: game.idempotency_key.clone(),
+            seed: Some(game.seed),
+            ruleset: Some(game.ruleset),
+            priority: game.priority,
+            attempt: game.attempt + 1,
+        };
+        Ok(serde_json::to_vec(&request)?)
+    }
+
+    /// Create a GameComplete message
+    fn create_game_complete_message(match_id: &str, details: &CompletionDetails) -> Value {
+        let mut message = json!({
+            "schema_version": SCHEMA_VERSION,
+            "match_id": match_id,
+            "status": "completed"
+        });
+        if let Some(reason) = &details.rejected {
+            message["status"] = json!("rejected");
+            message["error"] = json!(reason);
+        }
+        if details.cancelled {
+            message["status"] = json!("cancelled");
+        }
+        if let Some(error) = &details.error {
+            message["status"] = json!(if details.timed_out {
+                "timed_out"
+            } else {
+                "error"
+            });
+            message["error"] = json!(error);
+        }
+        if let Some(original) = &details.duplicate_of {
+            message["duplicate_of"] = json!(original);
+        }
+        if let Some(seed) = details.seed {
+            message["seed"] = json!(seed);
+        }
+        if let Some(ruleset) = details.ruleset {
+            message["ruleset"] = json!(ruleset.name());
+        }
+        if let Some(correlation_id) = &details.correlation_id {
+            message["correlation_id"] = json!(correlation_id);
+        }
+        if let Some(result) = &details.result {
+            let final_state = result.final_state.as_ref();
+            message["seats"] = json!(result
+                .seats
+                .iter()
+                .enumerate()
+                .map(|(seat, controller)| {
+                    let mut entry = json!({ "seat": seat, "controller": controller });
+                    if let Some(score) = final_state.and_then(|state| state.scores.get(seat)) {
+                        entry["score"] = json!(score);
+                    }
+                    entry
+                })
+                .collect::<Vec<_>>());
+            if let Some(state) = final_state {
+                let winner = state.leader();
+                message["winner"] = json!({
+                    "seat": winner,
+                    "controller": result.seats.get(winner),
+                });
+                message["final_state"] = json!(state);
+            }
+            if let Some(history) = &result.history {
+                message["history"] = json!({
+                    "turns": history.turns,
+                    "dropped_turns": history.dropped,
+                });
+            }
+        }
+        if let Some(timing) = &details.timing {
+            message["timing"] = json!({
+                "queue_wait_ms": timing.queue_wait.as_millis() as u64,
+                "run_ms": timing.run.as_millis() as u64,
+            });
+        }
+        message
+    }
+}
+
+/// A GameComplete message waiting in the outbox
+struct OutgoingCompletion {
+    match_id: String,
+    message: Value,
+    correlation_id: Option<String>,
+    /// When the game finished, to stamp `timing.publish_ms` on each attempt
+    finished_at: Option<Instant>,
+}
+
+/// Publishes GameComplete messages and republishes spilled ones on a task of
+/// its own, so the pool keeps taking messages while the broker is unavailable
+struct CompletionOutbox {
+    queue_client: QueueClient,
+    /// Where GameComplete messages that could not be published are kept
+    spill: Option<CompletionSpill>,
+    completions: mpsc::UnboundedReceiver<OutgoingCompletion>,
+    /// Set once a message had to be spilled. Later messages get a single
+    /// attempt before being spilled, rather than waiting out every retry.
+    broker_down: bool,
+}
+
+impl CompletionOutbox {
+    /// Publish completions until the pool closes the channel, then make a
+    /// last attempt at the spilled ones
+    async fn run(mut self) {
+        let mut spill_sweep = tokio::time::interval(SPILL_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                completion = self.completions.recv() => match completion {
+                    Some(completion) => self.publish(completion).await,
+                    None => break,
+                },
+                _ = spill_sweep.tick() => self.sweep_spill().await,
+            }
+        }
+        self.flush_spill().await;
+    }
+
+    /// Publish a GameComplete message, retrying with backoff and spilling it
+    /// to disk when publishing keeps failing
+    #[instrument(skip_all, fields(match_id = %completion.match_id))]
+    async fn publish(&mut self, mut completion: OutgoingCompletion) {
+        let match_id = completion.match_id.as_str();
+        info!("Publishing completion event for game: {}", match_id);
+
+        let attempts = if self.broker_down && self.spill.is_some() {
+            1
+        } else {
+            COMPLETION_PUBLISH_ATTEMPTS
+        };
+        let backoff = Backoff::new(COMPLETION_RETRY_BASE_DELAY, COMPLETION_RETRY_MAX_DELAY);
+        let mut attempt = 1;
+        let (game_complete_data, error) = loop {
+            if let Some(finished_at) = completion.finished_at {
+                completion.message["timing"]["publish_ms"] =
+                    json!(finished_at.elapsed().as_millis() as u64);
+            }
+            let game_complete_data = match serde_json::to_vec(&completion.message) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!(
+                        "Failed to encode game complete event for {}: {}",
+                        match_id, e
+                    );
+                    return;
+                }
+            };
+            match self
+                .queue_client
+                .publish_game_complete(
+                    match_id,
+                    &game_complete_data,
+                    Encoding::Json,
+                    completion.correlation_id.as_deref(),
+                )
+                .await
+            {
+                Ok(()) => {
+                    self.broker_down = false;
+                    return;
+                }
+                Err(e) if attempt >= attempts => break (game_complete_data, e),
+                Err(e) => {
+                    let delay = backoff.delay(attempt - 1);
+                    warn!(
+                        "Failed to publish game complete event for {} (attempt {}), retrying in {:?}: {}",
+                        match_id, attempt, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        };
+        error!("Failed to publish game complete event: {}", error);
+
+        let Some(spill) = &self.spill else {
+            return;
+        };
+        self.broker_down = true;
+        match spill.store(match_id, &game_complete_data).await {
+            Ok(path) => warn!(
+                "Spilled game complete event for {} to {}",
+                match_id,
+                path.display()
+            ),
+            Err(e) => error!(
+                "Failed to spill game complete event for {}: {}",
+                match_id,
+                PoolError::Spill(e)
+            ),
+        }
+    }
+
+    /// Republish spilled GameComplete messages oldest first,
+    /// stopping at the first failure as the broker is likely still unavailable
+    async fn sweep_spill(&mut self) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+        let entries = match spill.entries().await {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to read spilled game complete events: {}", e);
+                return;
+            }
+        };
+
+        for entry in entries {
+            if let Err(e) = self
+                .queue_client
+                .publish_game_complete(
+                    &entry.match_id,
+                    &entry.payload,
+                    Encoding::Json,
+                    entry.correlation_id.as_deref(),
+                )
+                .await
+            {
+                warn!(
+                    "Failed to republish spilled game complete event for {}: {}",
+                    entry.match_id, e
+                );
+                return;
+            }
+            info!(
+                "Republished spilled game complete event for {}",
+                entry.match_id
+            );
+            if let Err(e) = spill.remove(&entry).await {
+                error!(
+                    "Failed to remove republished spill file {}: {}",
+                    entry.path.display(),
+                    e
+                );
+            }
+        }
+        self.broker_down = false;
+    }
+    /// Make a last attempt to republish spilled GameComplete messages before
+    /// the pool exits, logging any still undelivered for manual recovery
+    async fn flush_spill(&mut self) {
+        self.sweep_spill().await;
+        let Some(spill) = &self.spill else {
+            return;
+        };
+        match spill.entries().await {
+            Ok(entries) => {
+                for entry in entries {
+                    error!(
+                        "Game complete event for {} was not delivered before shutdown, left in {}",
+                        entry.match_id,
+                        entry.path.display()
+                    );
+                }
+            }
+            Err(e) => error!("Failed to read spilled game complete events: {}", e),
+        }
+    }
+}
+
+/// Seed for a match derived from the master seed and its match id
+fn derive_seed(master_seed: u64, match_id: &str) -> u64 {
+    fnv1a(
+        master_seed
+            .to_le_bytes()
+            .into_iter()
+            .chain(match_id.bytes()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_transport::InMemoryTransport;
+    use crate::queue::Topics;
+    use crate::store::MatchCheckpoint;
+    use crate::transport::{
+        DeliveryHandler, DeliveryInfo, Disposition, MessagePredicate, MessageTransport,
+        PublishOptions,
+    };
+    use crate::workers::ScriptedRunner;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+    use tokio::task::JoinHandle;
+    use tokio_util::sync::{CancellationToken, DropGuard};
+
+    /// Longest a test waits on the pool before failing
+    const WAIT: Duration = Duration::from_secs(5);
+    /// Steps of a game that only ends when it is stopped
+    const ENDLESS: u64 = u64::MAX;
+
+    fn test_config(vars: &[(&str, &str)]) -> Config {
+        let vars = [
+            ("QUEUE_CLUSTER_URL", "amqp://localhost"),
+            ("WORKER_THREADS", "1"),
+        ]
+        .iter()
+        .chain(vars)
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        envy::from_iter(vars).expect("valid test config")
+    }
+
+    fn finished() -> GameStatus {
+        GameStatus::Finished(GameResult {
+            seats: vec!["alice".to_string(); 4],
+            final_state: Some(StateSnapshot {
+                state: "GameEnd".to_string(),
+                scores: [25_000, 32_000, 18_000, 25_000],
+            }),
+            history: None,
+        })
+    }
+
+    /// Checkpoints kept in memory, outliving the pools that use them
+    #[derive(Default)]
+    struct MemoryStore {
+        checkpoints: Mutex<HashMap<String, MatchCheckpoint>>,
+    }
+
+    impl MemoryStore {
+        fn match_ids(&self) -> Vec<String> {
+            self.checkpoints.lock().unwrap().keys().cloned().collect()
+        }
+    }
+
+    #[async_trait]
+    impl MatchStore for MemoryStore {
+        async fn save(&self, checkpoint: &MatchCheckpoint) -> std::io::Result<()> {
+            self.checkpoints
+                .lock()
+                .unwrap()
+                .insert(checkpoint.match_id.clone(), checkpoint.clone());
+            Ok(())
+        }
+
+        async fn remove(&self, match_id: &str) -> std::io::Result<()> {
+            self.checkpoints.lock().unwrap().remove(match_id);
+            Ok(())
+        }
+
+        async fn load_all(&self) -> std::io::Result<Vec<MatchCheckpoint>> {
+            Ok(self.checkpoints.lock().unwrap().values().cloned().collect())
+        }
+    }
+
+    /// JSON messages published to an exchange, collected through a queue bound to it
+    struct Tap {
+        messages: mpsc::UnboundedReceiver<Value>,
+        _consumer: DropGuard,
+    }
+
+    impl Tap {
+        async fn bind(transport: &Arc<InMemoryTransport>, exchange: &str) -> Self {
+            let queue = format!("tap.{}", exchange);
+            // Bind before returning, so nothing published from here on is missed
+            transport.check_ready(exchange, &queue, "#").await.unwrap();
+
+            let (sender, messages) = mpsc::unbounded_channel();
+            let shutdown = CancellationToken::new();
+            let consumer = shutdown.clone();
+            let transport = transport.clone();
+            let exchange = exchange.to_string();
+            tokio::spawn(async move {
+                let handler = move |_: &DeliveryInfo<'_>, data: &[u8]| {
+                    let _ = sender.send(serde_json::from_slice(data)?);
+                    Ok(Disposition::Ack)
+                };
+                transport
+                    .consume(&exchange, &queue, "#", &handler, consumer)
+                    .await
+            });
+            Self {
+                messages,
+                _consumer: shutdown.drop_guard(),
+            }
+        }
+
+        async fn next(&mut self) -> Value {
+            tokio::time::timeout(WAIT, self.messages.recv())
+                .await
+                .expect("no message published in time")
+                .expect("tap stopped")
+        }
+
+        /// Whether nothing more is published for a while
+        async fn is_quiet(&mut self) -> bool {
+            tokio::time::timeout(Duration::from_millis(200), self.messages.recv())
+                .await
+                .is_err()
+        }
+    }
+
+    /// A game pool running on the in-memory transport, with its completion events tapped
+    struct Harness {
+        transport: Arc<InMemoryTransport>,
+        pool: mpsc::Sender<GamePoolMessage>,
+        completions: Tap,
+        handle: JoinHandle<Result<(), PoolError>>,
+    }
+
+    impl Harness {
+        async fn start(config: &Config, runner: Arc<ScriptedRunner>) -> Self {
+            let transport = Arc::new(InMemoryTransport::default());
+            let pool = GamePool::with_runner(client(&transport, config), config, runner).unwrap();
+            Self::run(transport, config, pool).await
+        }
+
+        async fn run(transport: Arc<InMemoryTransport>, config: &Config, pool: GamePool) -> Self {
+            let completions = Tap::bind(&transport, &config.outgoing_topic).await;
+            let sender = pool.sender();
+            Self {
+                transport,
+                pool: sender,
+                completions,
+                handle: tokio::spawn(pool.run()),
+            }
+        }
+
+        /// Collect what the pool publishes to `exchange` from now on
+        async fn tap(&self, exchange: &str) -> Tap {
+            Tap::bind(&self.transport, exchange).await
+        }
+
+        fn submit(&self, request: StartGameRequest) {
+            request.submit(&self.pool, "", None).unwrap();
+        }
+
+        async fn send(&self, message: GamePoolMessage) {
+            self.pool.send(message).await.unwrap();
+        }
+
+        async fn status(&self) -> PoolStatus {
+            let (respond_to, status) = oneshot::channel();
+            self.send(GamePoolMessage::QueryActive { respond_to }).await;
+            status.await.unwrap()
+        }
+
+        async fn result(&self, match_id: &str) -> Option<Value> {
+            let (respond_to, result) = oneshot::channel();
+            self.send(GamePoolMessage::QueryResult {
+                match_id: match_id.to_string(),
+                respond_to,
+            })
+            .await;
+            result.await.unwrap()
+        }
+
+        async fn cancel(&self, match_id: &str) -> Option<MatchPhase> {
+            let (respond_to, phase) = oneshot::channel();
+            self.send(GamePoolMessage::CancelGame {
+                match_id: match_id.to_string(),
+                respond_to,
+            })
+            .await;
+            phase.await.unwrap()
+        }
+
+        /// Wait for the pool to stop running
+        async fn stopped(self) {
+            tokio::time::timeout(WAIT, self.handle)
+                .await
+                .expect("pool did not stop in time")
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    fn client(transport: &Arc<InMemoryTransport>, config: &Config) -> QueueClient {
+        QueueClient::with_transport(transport.clone(), Topics::from_config(config))
+    }
+
+    fn request(match_id: &str) -> StartGameRequest {
+        StartGameRequest::new(match_id, vec!["alice".to_string()])
+    }
+
+    #[tokio::test]
+    async fn publishes_completion_of_finished_game() {
+        let config = test_config(&[]);
+        let mut harness =
+            Harness::start(&config, Arc::new(ScriptedRunner::new(3, finished()))).await;
+
+        harness.submit(request("match-1"));
+
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-1");
+        assert_eq!(completion["status"], "completed");
+        assert_eq!(completion["seats"].as_array().unwrap().len(), 4);
+        assert_eq!(completion["seats"][1]["score"], 32_000);
+        assert_eq!(completion["winner"]["seat"], 1);
+        assert_eq!(completion["final_state"]["state"], "GameEnd");
+        assert!(completion["seed"].is_u64());
+        assert_eq!(harness.status().await.active_count, 0);
+    }
+
+    #[tokio::test]
+    async fn cancels_running_game() {
+        let config = test_config(&[]);
+        let mut harness =
+            Harness::start(&config, Arc::new(ScriptedRunner::new(ENDLESS, finished()))).await;
+
+        harness.submit(request("match-1"));
+        assert_eq!(harness.status().await.active_count, 1);
+
+        assert_eq!(harness.cancel("match-1").await, Some(MatchPhase::Active));
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-1");
+        assert_eq!(completion["status"], "cancelled");
+        assert_eq!(harness.status().await.active_count, 0);
+        assert_eq!(harness.cancel("match-1").await, None);
+    }
+
+    #[tokio::test]
+    async fn cancels_pending_match() {
+        let config = test_config(&[("MAX_CONCURRENT", "1")]);
+        let mut harness =
+            Harness::start(&config, Arc::new(ScriptedRunner::new(ENDLESS, finished()))).await;
+
+        harness.submit(request("match-1"));
+        request("match-2")
+            .submit(&harness.pool, "", Some("request-2"))
+            .unwrap();
+        let status = harness.status().await;
+        assert_eq!(status.pending_count, 1);
+        assert_eq!(status.pending_matches[0].match_id, "match-2");
+
+        assert_eq!(harness.cancel("match-2").await, Some(MatchPhase::Pending));
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-2");
+        assert_eq!(completion["status"], "cancelled");
+        assert_eq!(completion["correlation_id"], "request-2");
+
+        let status = harness.status().await;
+        assert_eq!(status.pending_count, 0);
+        assert!(status.pending_matches.is_empty());
+        assert_eq!(status.active_count, 1);
+        assert_eq!(harness.cancel("match-2").await, None);
+    }
+
+    #[tokio::test]
+    async fn resubmits_game_that_failed_to_start() {
+        let config = test_config(&[]);
+        let runner = ScriptedRunner::new(0, GameStatus::StartFailed("no engine".to_string()));
+        let mut harness = Harness::start(&config, Arc::new(runner)).await;
+        let mut incoming = harness.tap(&config.incoming_topic).await;
+
+        request("match-1")
+            .submit(&harness.pool, "", Some("corr-1"))
+            .unwrap();
+
+        let resubmitted = incoming.next().await;
+        // The correlation id travels as a message property, not in the body
+        assert!(resubmitted.get("correlation_id").is_none());
+        let resubmitted: StartGameRequest = serde_json::from_value(resubmitted).unwrap();
+        assert_eq!(resubmitted.match_id, "match-1");
+        assert_eq!(resubmitted.attempt, 2);
+        assert_eq!(resubmitted.ruleset, Some(Ruleset::Standard));
+        assert!(harness.completions.is_quiet().await);
+        assert_eq!(harness.status().await.active_count, 0);
+    }
+
+    #[tokio::test]
+    async fn gives_up_starting_after_last_attempt() {
+        let config = test_config(&[("MAX_START_ATTEMPTS", "2")]);
+        let runner = ScriptedRunner::new(0, GameStatus::StartFailed("no engine".to_string()));
+        let mut harness = Harness::start(&config, Arc::new(runner)).await;
+        let mut incoming = harness.tap(&config.incoming_topic).await;
+
+        harness.submit(StartGameRequest {
+            attempt: 2,
+            ..request("match-1")
+        });
+
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-1");
+        assert_eq!(completion["status"], "error");
+        assert_eq!(
+            completion["error"],
+            "Failed to start after 2 attempts: no engine"
+        );
+        assert!(incoming.is_quiet().await);
+    }
+
+    #[tokio::test]
+    async fn reports_timed_out_game() {
+        let config = test_config(&[("GAME_TIMEOUT_SECS", "1")]);
+        let mut harness =
+            Harness::start(&config, Arc::new(ScriptedRunner::new(ENDLESS, finished()))).await;
+
+        harness.submit(request("match-1"));
+
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-1");
+        assert_eq!(completion["status"], "timed_out");
+        assert_eq!(completion["error"], "Game timed out after 1s");
+        assert!(completion.get("seats").is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_game_ending_in_error() {
+        let config = test_config(&[]);
+        let runner = ScriptedRunner::new(3, GameStatus::Error("engine exploded".to_string()));
+        let mut harness = Harness::start(&config, Arc::new(runner)).await;
+
+        harness.submit(request("match-1"));
+
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-1");
+        assert_eq!(completion["status"], "error");
+        assert_eq!(completion["error"], "engine exploded");
+    }
+
+    #[tokio::test]
+    async fn runs_one_game_per_idempotency_key() {
+        let config = test_config(&[]);
+        let runner = Arc::new(ScriptedRunner::new(50, finished()));
+        let mut harness = Harness::start(&config, runner.clone()).await;
+        let keyed = |match_id: &str| StartGameRequest {
+            idempotency_key: Some("key-1".to_string()),
+            ..request(match_id)
+        };
+
+        harness.submit(keyed("match-1"));
+        keyed("match-2")
+            .submit(&harness.pool, "", Some("retry-2"))
+            .unwrap();
+
+        let original = harness.completions.next().await;
+        assert_eq!(original["match_id"], "match-1");
+        assert_eq!(original["status"], "completed");
+
+        // A resubmission while the game runs is answered with its result
+        let duplicate = harness.completions.next().await;
+        assert_eq!(duplicate["match_id"], "match-2");
+        assert_eq!(duplicate["duplicate_of"], "match-1");
+        assert_eq!(duplicate["correlation_id"], "retry-2");
+        assert_eq!(duplicate["status"], "completed");
+        assert_eq!(duplicate["seats"], original["seats"]);
+        assert_eq!(duplicate["seed"], original["seed"]);
+
+        // So is one after it finished
+        harness.submit(keyed("match-3"));
+        let duplicate = harness.completions.next().await;
+        assert_eq!(duplicate["match_id"], "match-3");
+        assert_eq!(duplicate["duplicate_of"], "match-1");
+        assert!(duplicate.get("correlation_id").is_none());
+        assert_eq!(duplicate["winner"], original["winner"]);
+
+        assert_eq!(runner.created.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn reports_timing_when_enabled() {
+        let config = test_config(&[("INCLUDE_TIMING", "true")]);
+        let mut harness =
+            Harness::start(&config, Arc::new(ScriptedRunner::new(3, finished()))).await;
+
+        harness.submit(request("match-1"));
+
+        let completion = harness.completions.next().await;
+        assert!(completion["timing"]["queue_wait_ms"].is_u64());
+        assert!(completion["timing"]["run_ms"].is_u64());
+        assert!(completion["timing"]["publish_ms"].is_u64());
+
+        let result = harness.result("match-1").await.unwrap();
+        assert_eq!(result["timing"]["run_ms"], completion["timing"]["run_ms"]);
+    }
+
+    #[tokio::test]
+    async fn omits_timing_by_default() {
+        let config = test_config(&[]);
+        let mut harness =
+            Harness::start(&config, Arc::new(ScriptedRunner::new(3, finished()))).await;
+
+        harness.submit(request("match-1"));
+
+        assert!(harness.completions.next().await.get("timing").is_none());
+        let result = harness.result("match-1").await.unwrap();
+        assert!(result.get("timing").is_none());
+    }
+
+    /// A transport whose publishes never complete, as with an unresponsive broker
+    struct StalledTransport(InMemoryTransport);
+
+    #[async_trait]
+    impl MessageTransport for StalledTransport {
+        async fn publish(
+            &self,
+            _exchange: &str,
+            _routing_key: &str,
+            _payload: &[u8],
+            _options: PublishOptions<'_>,
+        ) -> Result<(), QueueError> {
+            std::future::pending().await
+        }
+
+        async fn consume(
+            &self,
+            exchange: &str,
+            queue_name: &str,
+            binding_key: &str,
+            handler: &DeliveryHandler,
+            shutdown: CancellationToken,
+        ) -> Result<(), QueueError> {
+            self.0
+                .consume(exchange, queue_name, binding_key, handler, shutdown)
+                .await
+        }
+
+        async fn consume_until(
+            &self,
+            exchange: &str,
+            routing_key: &str,
+            timeout: Duration,
+            predicate: &MessagePredicate<'_>,
+        ) -> Result<Vec<u8>, QueueError> {
+            self.0
+                .consume_until(exchange, routing_key, timeout, predicate)
+                .await
+        }
+
+        fn is_consuming(&self) -> bool {
+            self.0.is_consuming()
+        }
+
+        async fn check_ready(
+            &self,
+            exchange: &str,
+            queue_name: &str,
+            binding_key: &str,
+        ) -> Result<(), QueueError> {
+            self.0.check_ready(exchange, queue_name, binding_key).await
+        }
+
+        async fn reconnect(&self) -> Result<(), QueueError> {
+            self.0.reconnect().await
+        }
+
+        async fn close(&self) -> Result<(), QueueError> {
+            self.0.close().await
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_answering_while_publishing_stalls() {
+        let config = test_config(&[]);
+        let stalled = Arc::new(StalledTransport(InMemoryTransport::default()));
+        let client = QueueClient::with_transport(stalled, Topics::from_config(&config));
+        let runner = Arc::new(ScriptedRunner::new(3, finished()));
+        let pool = GamePool::with_runner(client, &config, runner).unwrap();
+        let harness = Harness::run(Arc::new(InMemoryTransport::default()), &config, pool).await;
+
+        harness.submit(request("match-1"));
+        harness.submit(request("match-2"));
+
+        // Both games finish although the completion of the first is never published
+        tokio::time::timeout(WAIT, async {
+            while harness.result("match-2").await.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("pool stopped answering");
+        assert!(harness.result("match-1").await.is_some());
+        assert_eq!(harness.status().await.active_count, 0);
+    }
+
+    /// Run a match on a pool checkpointing to `store` and stop the pool while it
+    /// is running, as a crash would, returning the seed the match was played with
+    async fn interrupt_match(
+        transport: &Arc<InMemoryTransport>,
+        config: &Config,
+        store: &Arc<MemoryStore>,
+        match_id: &str,
+    ) -> Value {
+        let runner = Arc::new(ScriptedRunner::new(ENDLESS, finished()));
+        let pool = GamePool::with_runner(client(transport, config), config, runner)
+            .unwrap()
+            .with_store(store.clone());
+        let harness = Harness::run(transport.clone(), config, pool).await;
+
+        harness.submit(request(match_id));
+        assert_eq!(harness.status().await.active_count, 1);
+        harness.send(GamePoolMessage::Shutdown).await;
+        harness.stopped().await;
+
+        let checkpoints = store.load_all().await.unwrap();
+        assert_eq!(checkpoints.len(), 1);
+        json!(checkpoints[0].seed)
+    }
+
+    #[tokio::test]
+    async fn resubmits_interrupted_match_on_restart() {
+        let config = test_config(&[]);
+        let transport = Arc::new(InMemoryTransport::default());
+        let store = Arc::new(MemoryStore::default());
+        let seed = interrupt_match(&transport, &config, &store, "match-1").await;
+
+        let mut incoming = Tap::bind(&transport, &config.incoming_topic).await;
+        let runner = Arc::new(ScriptedRunner::new(ENDLESS, finished()));
+        let pool = GamePool::with_runner(client(&transport, &config), &config, runner)
+            .unwrap()
+            .with_store(store.clone());
+        let _harness = Harness::run(transport.clone(), &config, pool).await;
+
+        let resubmitted = incoming.next().await;
+        assert_eq!(resubmitted["match_id"], "match-1");
+        assert_eq!(resubmitted["attempt"], 2);
+        assert_eq!(resubmitted["seed"], seed);
+        assert!(store.match_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancels_interrupted_match_without_attempts_left() {
+        let config = test_config(&[("MAX_START_ATTEMPTS", "1")]);
+        let transport = Arc::new(InMemoryTransport::default());
+        let store = Arc::new(MemoryStore::default());
+        interrupt_match(&transport, &config, &store, "match-1").await;
+
+        let runner = Arc::new(ScriptedRunner::new(ENDLESS, finished()));
+        let pool = GamePool::with_runner(client(&transport, &config), &config, runner)
+            .unwrap()
+            .with_store(store.clone());
+        let mut harness = Harness::run(transport.clone(), &config, pool).await;
+
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-1");
+        assert_eq!(completion["status"], "cancelled");
+        assert!(store.match_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn drain_lets_running_games_finish() {
+        let config = test_config(&[]);
+        let mut harness =
+            Harness::start(&config, Arc::new(ScriptedRunner::new(50, finished()))).await;
+
+        harness.submit(request("match-1"));
+        harness
+            .send(GamePoolMessage::Drain {
+                deadline: Instant::now() + WAIT,
+            })
+            .await;
+
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-1");
+        assert_eq!(completion["status"], "completed");
+        harness.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn drain_hands_pending_games_back_to_queue() {
+        let config = test_config(&[("MAX_CONCURRENT", "1")]);
+        let mut harness =
+            Harness::start(&config, Arc::new(ScriptedRunner::new(ENDLESS, finished()))).await;
+        let mut incoming = harness.tap(&config.incoming_topic).await;
+
+        harness.submit(request("match-1"));
+        harness.submit(StartGameRequest {
+            priority: 5,
+            seed: Some(7),
+            ..request("match-2")
+        });
+        assert_eq!(harness.status().await.pending_count, 1);
+        harness
+            .send(GamePoolMessage::Drain {
+                deadline: Instant::now() + Duration::from_millis(100),
+            })
+            .await;
+
+        let handed_back = incoming.next().await;
+        assert_eq!(handed_back["match_id"], "match-2");
+        assert_eq!(handed_back["priority"], 5);
+        assert_eq!(handed_back["seed"], 7);
+        assert_eq!(handed_back["attempt"], 1);
+        // Only the running game is answered, once the deadline aborts it
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-1");
+        assert_eq!(completion["status"], "cancelled");
+        harness.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn hands_back_matches_arriving_while_draining() {
+        let config = test_config(&[]);
+        let mut harness =
+            Harness::start(&config, Arc::new(ScriptedRunner::new(ENDLESS, finished()))).await;
+        let mut incoming = harness.tap(&config.incoming_topic).await;
+
+        harness.submit(request("match-1"));
+        harness
+            .send(GamePoolMessage::Drain {
+                deadline: Instant::now() + WAIT,
+            })
+            .await;
+        request("match-2")
+            .submit(&harness.pool, "region.eu", Some("request-2"))
+            .unwrap();
+
+        let handed_back = incoming.next().await;
+        assert_eq!(handed_back["match_id"], "match-2");
+        assert_eq!(harness.status().await.active_count, 1);
+
+        // The drain still waits on the running game
+        assert_eq!(harness.cancel("match-1").await, Some(MatchPhase::Active));
+        assert_eq!(harness.completions.next().await["match_id"], "match-1");
+        assert!(harness.completions.is_quiet().await);
+        harness.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn drain_aborts_games_at_deadline() {
+        let config = test_config(&[]);
+        let runner = Arc::new(ScriptedRunner::new(ENDLESS, finished()));
+        let mut harness = Harness::start(&config, runner).await;
+
+        harness.submit(request("match-1"));
+        assert_eq!(harness.status().await.active_count, 1);
+        harness
+            .send(GamePoolMessage::Drain {
+                deadline: Instant::now() + Duration::from_millis(100),
+            })
+            .await;
+
+        // Without a match store nothing would recover the game, so it is answered
+        let completion = harness.completions.next().await;
+        assert_eq!(completion["match_id"], "match-1");
+        assert_eq!(completion["status"], "cancelled");
+        harness.stopped().await;
     }
 }
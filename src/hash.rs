@@ -0,0 +1,9 @@
+//! Hashing that stays the same across Rust releases
+
+/// FNV-1a hash of `bytes`. Used where the hash is persisted or must be
+/// reproducible, as the output of the std hasher may change between releases.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,93 @@
+//! Liveness and readiness endpoints for HTTP load balancers and probes
+
+use anyhow::{anyhow, Result};
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
+
+use crate::game_pool::GamePoolMessage;
+use crate::queue::QueueClient;
+
+/// How long `/readyz` waits on a busy game pool before reporting not ready
+const POOL_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+struct HealthState {
+    queue_client: QueueClient,
+    pool: mpsc::Sender<GamePoolMessage>,
+}
+
+/// Serve `/healthz` and `/readyz` until the task is aborted
+pub async fn serve(
+    port: u16,
+    queue_client: QueueClient,
+    pool: mpsc::Sender<GamePoolMessage>,
+) -> Result<()> {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(HealthState { queue_client, pool });
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(|e| anyhow!("Failed to bind health port {}: {}", port, e))?;
+    info!("Serving health checks on port {}", port);
+
+    axum::serve(listener, app)
+        .await
+        .map_err(|e| anyhow!("Health server failed: {}", e))
+}
+
+/// The process is up and serving requests
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Ready while the queue consumer is taking deliveries and the game pool is
+/// starting games, 503 while reconnecting, paused, draining or stopped
+async fn readyz(State(state): State<HealthState>) -> (StatusCode, Json<Value>) {
+    let consuming = state.queue_client.is_consuming();
+    let accepting = pool_accepting(&state.pool, POOL_QUERY_TIMEOUT).await;
+
+    let ready = consuming && accepting;
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = json!({
+        "ready": ready,
+        "consuming": consuming,
+        "accepting": accepting,
+    });
+    (code, Json(body))
+}
+
+/// Whether the game pool is accepting games, false if it does not answer
+/// within `timeout`
+async fn pool_accepting(pool: &mpsc::Sender<GamePoolMessage>, timeout: Duration) -> bool {
+    let query = async {
+        let (respond_to, response) = oneshot::channel();
+        pool.send(GamePoolMessage::QueryActive { respond_to })
+            .await
+            .ok()?;
+        response.await.ok()
+    };
+    matches!(
+        tokio::time::timeout(timeout, query).await,
+        Ok(Some(status)) if status.accepting
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unresponsive_pool_is_not_ready() {
+        let (pool, _receiver) = mpsc::channel(1);
+        assert!(!pool_accepting(&pool, Duration::from_millis(50)).await);
+    }
+}
@@ -1,19 +1,44 @@
+mod admin;
+mod cache;
 mod cli;
 mod config;
 mod controllers;
+mod files;
 mod game;
 mod game_pool;
+mod hash;
+mod health;
+#[cfg(test)]
+mod memory_transport;
+mod metrics;
 mod queue;
+mod spill;
+mod store;
+mod tls;
+mod transport;
+mod workers;
 
 use anyhow::Result;
 use clap::Parser;
-use cli::{Cli, Command, Tool};
+use cli::{Cli, Command, HealthCheck, Tool};
 use config::Config;
-use game_pool::{GamePool, GamePoolMessage};
-use queue::QueueClient;
-use serde_json::json;
+use game::{AdvanceOutcome, GameMatch, Ruleset};
+use game_pool::{GamePool, GamePoolMessage, PoolError, StartGameRequest};
+use queue::{Encoding, IncomingMessage, QueueClient, QueueError};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Semaphore};
 use tokio::{signal, task::JoinSet};
-use tracing::{error, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use transport::{DeliveryInfo, Disposition};
+
+/// Extra time given to the game pool past its drain deadline before forcing shutdown
+const DRAIN_MARGIN: Duration = Duration::from_secs(5);
+/// Time given to the game pool to abort its games after a forced shutdown,
+/// before its task is aborted along with the other services
+const FORCED_SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -23,121 +48,325 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
-    if cli.health_check {
-        return run_health_check().await;
+    if let Some(check) = cli.health_check {
+        return run_health_check(check, cli.config.as_deref()).await;
     }
 
     match cli.command {
-        Some(Command::Tools { tool }) => run_tools(tool).await,
-        _ => run_service().await,
+        Some(Command::Tools { tool }) => run_tools(tool, cli.config.as_deref()).await,
+        _ => run_service(cli.config.as_deref()).await,
     }
 }
 
-async fn run_tools(tool: Tool) -> Result<()> {
+async fn run_tools(tool: Tool, config_path: Option<&Path>) -> Result<()> {
     info!("Executing tool: {:?}", tool);
 
-    info!("Loading configuration from environment variables");
-    let config = Config::try_from_env()?;
-
     match tool {
-        Tool::QueueMatch { players } => {
+        Tool::QueueMatch {
+            players,
+            routing_key,
+            timeout_secs,
+            count,
+            concurrency,
+        } => {
+            info!("Loading configuration");
+            let config = Config::try_load(config_path)?;
+
             info!("Connecting to queue cluster...");
-            let queue_client = QueueClient::new(&config.queue_cluster_url).await?;
+            let queue_client = QueueClient::new(&config).await?;
+            let timeout = Duration::from_secs(timeout_secs);
+
+            let result = if count > 1 {
+                queue_batch(
+                    &queue_client,
+                    count,
+                    concurrency,
+                    &players,
+                    &routing_key,
+                    timeout,
+                )
+                .await
+            } else {
+                let match_id = format!("match_{}", chrono::Utc::now().timestamp());
+                queue_match(&queue_client, match_id, &players, &routing_key, timeout)
+                    .await
+                    .map(|(data, _)| {
+                        let message = String::from_utf8_lossy(&data);
+                        info!("Received match result: {}", message);
+                    })
+            };
+            let _ = queue_client.close().await;
+            result?;
+        }
+        Tool::DumpConfig => {
+            let config = Config::try_load(config_path)?;
+            println!("{}", serde_json::to_string_pretty(&config.redacted())?);
+        }
+        Tool::Replay {
+            seed,
+            ruleset,
+            players,
+        } => {
+            // The engine is synchronous, keep it off the runtime threads
+            tokio::task::spawn_blocking(move || run_replay(seed, ruleset, players)).await??;
+        }
+    }
 
-            let match_id = format!("match_{}", chrono::Utc::now().timestamp());
-            info!("Queuing match {} for players: {:?}", match_id, players);
+    Ok(())
+}
 
-            let result_handle = {
-                let queue_client = queue_client.clone();
-                let topic = queue_client.outgoing_topic().to_string();
-                let match_id = match_id.clone();
-                tokio::spawn(async move {
-                    info!(
-                        "Waiting for match result on topic '{}' with routing key '{}'",
-                        topic, match_id
-                    );
-                    match queue_client.consume_one(&topic, &match_id).await {
-                        Ok(data) => {
-                            let message = String::from_utf8_lossy(&data);
-                            info!("Received match result: {}", message);
-                        }
-                        Err(e) => {
-                            error!("Failed to receive match result: {}", e);
-                        }
-                    }
-                })
+/// Queue a match and wait for its result, returning the GameComplete message
+/// along with the time from publishing the match to receiving it
+async fn queue_match(
+    queue_client: &QueueClient,
+    match_id: String,
+    players: &[String],
+    routing_key: &str,
+    timeout: Duration,
+) -> Result<(Vec<u8>, Duration)> {
+    let correlation_id = uuid::Uuid::new_v4().to_string();
+    info!(
+        "Queuing match {} for players: {:?} with correlation id {}",
+        match_id, players, correlation_id
+    );
+
+    // Start waiting before publishing so a fast result is not missed
+    let result_handle = {
+        let queue_client = queue_client.clone();
+        let topic = queue_client.outgoing_topic().to_string();
+        let match_id = match_id.clone();
+        let correlation_id = correlation_id.clone();
+        tokio::spawn(async move {
+            info!(
+                "Waiting for match result on topic '{}' with routing key '{}'",
+                topic, match_id
+            );
+            // A match rerun under the same id shares the routing key,
+            // only the correlation id tells its result apart from ours
+            let is_this_match = |data: &[u8]| {
+                serde_json::from_slice::<serde_json::Value>(data)
+                    .is_ok_and(|message| message["correlation_id"] == correlation_id.as_str())
             };
+            queue_client
+                .consume_until(&topic, &match_id, timeout, is_this_match)
+                .await
+        })
+    };
 
-            let message = json!({
-                "match_id": match_id,
-                "players": players
-            });
-            let data = serde_json::to_vec(&message)?;
+    let request = StartGameRequest::new(match_id.clone(), players.to_vec());
+    let data = serde_json::to_vec(&request)?;
+
+    let queued_at = Instant::now();
+    if let Err(e) = queue_client
+        .publish_game_starting(routing_key, &data, Encoding::Json, Some(&correlation_id))
+        .await
+    {
+        // No result can come for a match that was never queued
+        result_handle.abort();
+        anyhow::bail!("Failed to queue match {}: {}", match_id, e);
+    }
 
-            if let Err(e) = queue_client.publish_game_starting(&data).await {
-                error!("Failed to queue match: {}", e);
-            }
+    // Wait for the result to be received
+    match result_handle.await? {
+        Ok(data) => Ok((data, queued_at.elapsed())),
+        Err(QueueError::Timeout(_)) => {
+            anyhow::bail!(
+                "No result for match {} within {} seconds",
+                match_id,
+                timeout.as_secs()
+            );
+        }
+        Err(e) => {
+            anyhow::bail!("Failed to receive match {} result: {}", match_id, e);
+        }
+    }
+}
 
-            // Wait for the result to be received
-            result_handle.await?;
+/// Queue `count` matches, at most `concurrency` awaiting their result at a time
+/// (all of them when unset), and report how long they took to complete
+async fn queue_batch(
+    queue_client: &QueueClient,
+    count: usize,
+    concurrency: Option<usize>,
+    players: &[String],
+    routing_key: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let permits = Arc::new(Semaphore::new(concurrency.unwrap_or(count).max(1)));
+    let batch_id = chrono::Utc::now().timestamp();
+    let started_at = Instant::now();
+
+    let mut matches = JoinSet::new();
+    for index in 0..count {
+        let queue_client = queue_client.clone();
+        let permits = permits.clone();
+        let match_id = format!("match_{}_{}", batch_id, index);
+        let players = players.to_vec();
+        let routing_key = routing_key.to_string();
+        matches.spawn(async move {
+            let _permit = permits.acquire_owned().await?;
+            queue_match(&queue_client, match_id, &players, &routing_key, timeout)
+                .await
+                .map(|(_, elapsed)| elapsed)
+        });
+    }
+
+    let mut completed = Vec::with_capacity(count);
+    while let Some(result) = matches.join_next().await {
+        match result? {
+            Ok(elapsed) => completed.push(elapsed),
+            Err(e) => warn!("{}", e),
         }
     }
 
+    let failed = count - completed.len();
+    match (completed.iter().min(), completed.iter().max()) {
+        (Some(min), Some(max)) => {
+            let total: Duration = completed.iter().sum();
+            info!(
+                "Completed {} of {} matches in {:?}, time to complete: min {:?}, max {:?}, avg {:?}",
+                completed.len(),
+                count,
+                started_at.elapsed(),
+                min,
+                max,
+                total / completed.len() as u32
+            );
+        }
+        _ => info!("None of the {} matches completed", count),
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} matches did not complete", failed, count);
+    }
     Ok(())
 }
 
-async fn run_health_check() -> Result<()> {
-    // Just ensures we can load config and connect to the queue.
-    let config = Config::try_from_env()?;
-    let queue_client = QueueClient::new(&config.queue_cluster_url).await?;
-    queue_client.close().await?;
-    info!("Health check successful.");
+/// Run a match to completion locally, printing the observed state after every step
+fn run_replay(seed: u64, ruleset: Ruleset, players: Vec<String>) -> Result<()> {
+    let match_id = format!("replay_{}", seed);
+    let controllers = controllers::seat_players(&players, &[]);
+    info!(
+        "Replaying match with seed {}, {} rules and controllers: {:?}",
+        seed,
+        ruleset.name(),
+        controllers
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+    );
+
+    let mut game_match = GameMatch::try_new(match_id, controllers, seed, ruleset)?;
+    let mut step = 0;
+    loop {
+        let outcome = game_match.advance()?;
+        step += 1;
+        match game_match.observe_state() {
+            Some(observed) => println!("[{}] {:?}", step, observed),
+            None => println!("[{}] <state consumed>", step),
+        }
+        if outcome == AdvanceOutcome::Finished {
+            break;
+        }
+    }
+
+    info!("Replay finished after {} steps", step);
     Ok(())
 }
 
-async fn run_service() -> Result<()> {
+async fn run_health_check(check: HealthCheck, config_path: Option<&Path>) -> Result<()> {
+    // Liveness ensures we can load config and connect to the queue,
+    // readiness also ensures games could actually be consumed.
+    let config = Config::try_load(config_path)?;
+    let queue_client = QueueClient::new(&config).await?;
+    let result = match check {
+        HealthCheck::Liveness => Ok(()),
+        HealthCheck::Readiness => {
+            queue_client
+                .check_ready(&config.incoming_queue_name, &config.binding_key)
+                .await
+        }
+    };
+    let closed = queue_client.close().await;
+    result?;
+    closed?;
+    info!("Health check successful ({:?}).", check);
+    Ok(())
+}
+
+async fn run_service(config_path: Option<&Path>) -> Result<()> {
     info!("It's-a Super Gametable!");
 
-    info!("Loading configuration from environment variables");
-    let config = Config::try_from_env()?;
+    info!("Loading configuration");
+    let config = Config::try_load(config_path)?;
+
+    // Serve metrics independently of the services, so it does not hold up draining
+    let metrics_server = match config.metrics_port {
+        Some(port) => {
+            let handle = metrics::install()?;
+            Some(tokio::spawn(async move {
+                if let Err(e) = metrics::serve(port, handle).await {
+                    error!("{}", e);
+                }
+            }))
+        }
+        None => None,
+    };
 
     // --- Create shared clients ---
     info!("Connecting to queue cluster...");
-    let queue_client = QueueClient::new(&config.queue_cluster_url).await?;
+    let queue_client = QueueClient::new(&config).await?;
 
     // --- Create and wire up services ---
-    let game_pool = GamePool::new(queue_client.clone());
+    let game_pool = GamePool::new(queue_client.clone(), &config)?;
     let game_pool_sender = game_pool.sender();
 
-    let game_starting_handler = {
+    // Like metrics, the admin API is left out of the services shutdown waits on
+    let admin_server = match (config.admin_port, config.admin_token.clone()) {
+        (Some(port), Some(token)) => {
+            let sender = game_pool_sender.clone();
+            let queue_client = queue_client.clone();
+            Some(tokio::spawn(async move {
+                if let Err(e) = admin::serve(port, &token, sender, queue_client).await {
+                    error!("{}", e);
+                }
+            }))
+        }
+        _ => None,
+    };
+
+    // Like the admin API, probes keep answering while the services shut down
+    let health_server = config.health_port.map(|port| {
+        let queue_client = queue_client.clone();
         let sender = game_pool_sender.clone();
-        move |data: &[u8]| -> Result<()> {
-            // TODO We need to back this with the spec crate
-            let message: serde_json::Value = serde_json::from_slice(data)?;
-            info!("Processing GameStarting message: {}", message);
-
-            let match_id = message["match_id"].as_str().unwrap_or("").to_string();
-            let players: Vec<String> = message["players"].as_array().map_or_else(Vec::new, |arr| {
-                arr.iter()
-                    .map(|v| v.as_str().unwrap_or("").to_string())
-                    .collect()
-            });
-
-            if let Err(e) = sender.try_send(GamePoolMessage::StartGame { match_id, players }) {
-                error!("Failed to send start game message: {}", e);
+        tokio::spawn(async move {
+            if let Err(e) = health::serve(port, queue_client, sender).await {
+                error!("{}", e);
             }
+        })
+    });
 
-            Ok(())
+    let game_starting_handler = {
+        let sender = game_pool_sender.clone();
+        move |message: IncomingMessage, info: &DeliveryInfo<'_>| {
+            handle_game_starting(&sender, message, info)
         }
     };
 
+    let drain_grace = Duration::from_secs(config.drain_grace_secs);
     let mut services = JoinSet::new();
 
     // Start the queue consumer
-    services.spawn(async move {
+    let consumer_shutdown = CancellationToken::new();
+    let shutdown = consumer_shutdown.clone();
+    let _consumer_handle = services.spawn(async move {
         info!("Queue consumer starting.");
         if let Err(e) = queue_client
-            .start_consuming(&config.incoming_queue_name, game_starting_handler)
+            .start_consuming(
+                &config.incoming_queue_name,
+                &config.binding_key,
+                game_starting_handler,
+                shutdown,
+            )
             .await
         {
             error!("Queue consumer failed: {}", e);
@@ -156,29 +385,409 @@ async fn run_service() -> Result<()> {
 
     // --- Run until shutdown ---
     info!("Super Gametable is running. Press Ctrl+C to shutdown.");
-    tokio::select! {
-        _ = signal::ctrl_c() => {
-            info!("Shutdown signal received.");
+    let drain = tokio::select! {
+        signal = shutdown_signal() => {
+            info!("{} received.", signal.name());
+            signal.drains()
         },
         Some(res) = services.join_next() => {
             error!("A service task failed: {:?}", res);
+            true
         },
-    }
+    };
 
     info!("Shutting down...");
 
-    // Send shutdown message to game pool
-    if let Err(e) = game_pool_sender.send(GamePoolMessage::Shutdown).await {
-        error!("Failed to send shutdown message to game pool: {}", e);
+    // Stop taking new matches off the queue, letting the consumer finish its current delivery
+    consumer_shutdown.cancel();
+
+    let drained = if drain {
+        // Let active games finish, the pool aborts whatever is left at the deadline
+        let deadline = Instant::now() + drain_grace;
+        if let Err(e) = game_pool_sender
+            .send(GamePoolMessage::Drain { deadline })
+            .await
+        {
+            error!("Failed to send drain message to game pool: {}", e);
+        }
+
+        let drained = tokio::time::timeout(drain_grace + DRAIN_MARGIN, async {
+            while (services.join_next().await).is_some() {}
+        })
+        .await
+        .is_ok();
+        if !drained {
+            match active_game_count(&game_pool_sender).await {
+                Some(active) => error!(
+                    "Game pool did not drain in time, forcing shutdown with {} games still active",
+                    active
+                ),
+                None => error!(
+                    "Game pool did not drain in time and is not responding, forcing shutdown"
+                ),
+            }
+        }
+        drained
+    } else {
+        info!("Stopping without draining, active games are aborted");
+        false
+    };
+
+    // Fall back to a hard shutdown if the pool overran its deadline or is not drained
+    if !drained {
+        if let Err(e) = game_pool_sender.try_send(GamePoolMessage::Shutdown) {
+            error!("Failed to send shutdown message to game pool: {}", e);
+        }
+        let _ = tokio::time::timeout(FORCED_SHUTDOWN_GRACE, async {
+            while (services.join_next().await).is_some() {}
+        })
+        .await;
     }
 
-    // Abort all tasks in the JoinSet to signal them to shut down.
+    // Abort whatever is still running in the JoinSet.
     // This will cause the loop below to resolve.
     services.abort_all();
 
     // Wait for all tasks to complete.
     while (services.join_next().await).is_some() {}
 
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
+    if let Some(admin_server) = admin_server {
+        admin_server.abort();
+    }
+    if let Some(health_server) = health_server {
+        health_server.abort();
+    }
+
     info!("Super Gametable shut down gracefully.");
     Ok(())
 }
+
+/// Hand a consumed GameStarting message to the game pool. Malformed messages
+/// fail, rejecting them, and messages the pool cannot take yet are requeued.
+fn handle_game_starting(
+    sender: &mpsc::Sender<GamePoolMessage>,
+    message: IncomingMessage,
+    info: &DeliveryInfo<'_>,
+) -> Result<Disposition> {
+    // TODO We need to back this with the spec crate
+    let message = match message {
+        IncomingMessage::Json(message) => message,
+        IncomingMessage::Capnp(data) => anyhow::bail!(
+            "Cap'n Proto GameStarting messages are not supported yet ({} bytes)",
+            data.len()
+        ),
+    };
+    info!("Processing GameStarting message: {}", message);
+
+    // Malformed messages are rejected, dead-lettering them when configured
+    let request: StartGameRequest = serde_json::from_value(message)
+        .map_err(|e| anyhow::anyhow!("Malformed GameStarting message: {}", e))?;
+    tracing::Span::current().record("match_id", request.match_id.as_str());
+
+    // Leave the match on the queue rather than dropping it when the pool is behind
+    match request.submit(sender, info.routing_key, info.correlation_id) {
+        Ok(()) => Ok(Disposition::Ack),
+        Err(e @ (PoolError::Busy(_) | PoolError::Stopped(_))) => {
+            warn!("{}, requeueing GameStarting message", e);
+            Ok(Disposition::Requeue)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Signal that started a shutdown
+#[derive(Debug, Clone, Copy)]
+enum ShutdownSignal {
+    /// Ctrl+C, usually from a terminal
+    Interrupt,
+    /// SIGTERM, as sent by orchestrators such as Kubernetes
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Terminate,
+}
+
+impl ShutdownSignal {
+    fn name(self) -> &'static str {
+        match self {
+            ShutdownSignal::Interrupt => "Ctrl+C",
+            ShutdownSignal::Terminate => "SIGTERM",
+        }
+    }
+
+    /// Whether active games are given the drain grace period to finish.
+    /// Only platforms that can tell SIGTERM apart stop quickly on Ctrl+C.
+    fn drains(self) -> bool {
+        match self {
+            ShutdownSignal::Interrupt => cfg!(not(unix)),
+            ShutdownSignal::Terminate => true,
+        }
+    }
+}
+
+/// Wait for Ctrl+C, or SIGTERM where supported
+async fn shutdown_signal() -> ShutdownSignal {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal as unix_signal, SignalKind};
+        match unix_signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                return tokio::select! {
+                    _ = signal::ctrl_c() => ShutdownSignal::Interrupt,
+                    _ = terminate.recv() => ShutdownSignal::Terminate,
+                };
+            }
+            Err(e) => warn!(
+                "Failed to listen for SIGTERM, only Ctrl+C shuts down: {}",
+                e
+            ),
+        }
+    }
+    if let Err(e) = signal::ctrl_c().await {
+        error!("Failed to listen for Ctrl+C: {}", e);
+        std::future::pending::<()>().await;
+    }
+    ShutdownSignal::Interrupt
+}
+
+/// Number of games the pool reports as running, `None` if it does not answer in time
+async fn active_game_count(sender: &mpsc::Sender<GamePoolMessage>) -> Option<usize> {
+    let (respond_to, response) = oneshot::channel();
+    sender
+        .try_send(GamePoolMessage::QueryActive { respond_to })
+        .ok()?;
+    let status = tokio::time::timeout(FORCED_SHUTDOWN_GRACE, response)
+        .await
+        .ok()?
+        .ok()?;
+    Some(status.active.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_transport::InMemoryTransport;
+    use crate::queue::Topics;
+    use crate::transport::{DeliveryInfo, Disposition, MessageTransport, PublishOptions};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    /// Answer every GameStarting message with a GameComplete carrying its
+    /// correlation id, tracking how many matches were awaiting an answer at once
+    async fn respond_to_matches(
+        transport: Arc<InMemoryTransport>,
+        topics: &Topics,
+        most_in_flight: Arc<AtomicUsize>,
+    ) -> CancellationToken {
+        transport
+            .check_ready(&topics.incoming, "responder", "#")
+            .await
+            .unwrap();
+
+        let (sender, mut requests) = mpsc::unbounded_channel::<(String, String)>();
+        let shutdown = CancellationToken::new();
+        let consumer = {
+            let transport = transport.clone();
+            let exchange = topics.incoming.clone();
+            let shutdown = shutdown.clone();
+            async move {
+                let handler = move |info: &DeliveryInfo<'_>, data: &[u8]| {
+                    let request: StartGameRequest = serde_json::from_slice(data)?;
+                    let correlation_id = info.correlation_id.unwrap_or_default().to_string();
+                    let _ = sender.send((request.match_id, correlation_id));
+                    Ok(Disposition::Ack)
+                };
+                transport
+                    .consume(&exchange, "responder", "#", &handler, shutdown)
+                    .await
+            }
+        };
+        tokio::spawn(consumer);
+
+        let outgoing = topics.outgoing.clone();
+        tokio::spawn(async move {
+            let in_flight = Arc::new(AtomicUsize::new(0));
+            while let Some((match_id, correlation_id)) = requests.recv().await {
+                let awaiting = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(awaiting, Ordering::SeqCst);
+
+                let in_flight = in_flight.clone();
+                let transport = transport.clone();
+                let outgoing = outgoing.clone();
+                tokio::spawn(async move {
+                    // Give the client time to start waiting before answering
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    let message = serde_json::json!({
+                        "match_id": match_id,
+                        "status": "completed",
+                        "correlation_id": correlation_id,
+                    });
+                    let options = PublishOptions {
+                        content_type: "application/json",
+                        persistent: true,
+                        correlation_id: Some(&correlation_id),
+                        schema_version: None,
+                    };
+                    transport
+                        .publish(
+                            &outgoing,
+                            &match_id,
+                            &message.to_string().into_bytes(),
+                            options,
+                        )
+                        .await
+                });
+            }
+        });
+        shutdown
+    }
+
+    /// Run a GameStarting body through the consumer's decoding and handler,
+    /// returning the outcome and the request that reached the pool, if any
+    fn game_starting(
+        sender: &mpsc::Sender<GamePoolMessage>,
+        pool: &mut mpsc::Receiver<GamePoolMessage>,
+        body: &[u8],
+    ) -> (Result<Disposition>, Option<StartGameRequest>) {
+        let info = DeliveryInfo {
+            routing_key: "",
+            content_type: Some("application/json"),
+            correlation_id: None,
+        };
+        let outcome = IncomingMessage::decode(info.content_type, body)
+            .map_err(anyhow::Error::from)
+            .and_then(|message| handle_game_starting(sender, message, &info));
+        let request = match pool.try_recv() {
+            Ok(GamePoolMessage::StartGame { request, .. }) => Some(request),
+            _ => None,
+        };
+        (outcome, request)
+    }
+
+    /// Error a malformed GameStarting body is rejected with, checking that
+    /// nothing reached the pool
+    fn rejection(body: &[u8]) -> String {
+        let (sender, mut pool) = mpsc::channel(1);
+        let (outcome, request) = game_starting(&sender, &mut pool, body);
+        assert!(request.is_none(), "malformed message reached the pool");
+        format!("{:#}", outcome.unwrap_err())
+    }
+
+    #[test]
+    fn accepts_well_formed_game_starting_message() {
+        let (sender, mut pool) = mpsc::channel(1);
+        let body = br#"{"match_id": "match-1", "players": ["alice"]}"#;
+        let (outcome, request) = game_starting(&sender, &mut pool, body);
+        assert_eq!(outcome.unwrap(), Disposition::Ack);
+        let request = request.unwrap();
+        assert_eq!(request.match_id, "match-1");
+        assert_eq!(request.players, ["alice"]);
+    }
+
+    #[test]
+    fn requeues_game_starting_message_while_pool_is_busy() {
+        let (sender, mut pool) = mpsc::channel(1);
+        StartGameRequest::new("match-0", Vec::new())
+            .submit(&sender, "", None)
+            .unwrap();
+        let body = br#"{"match_id": "match-1", "players": ["alice"]}"#;
+        let (outcome, _) = game_starting(&sender, &mut pool, body);
+        assert_eq!(outcome.unwrap(), Disposition::Requeue);
+        assert!(pool.try_recv().is_err());
+    }
+
+    #[test]
+    fn rejects_game_starting_message_without_match_id() {
+        let error = rejection(br#"{"players": ["alice"]}"#);
+        assert!(error.contains("missing field `match_id`"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_game_starting_message_with_empty_match_id() {
+        let error = rejection(br#"{"match_id": "  ", "players": ["alice"]}"#);
+        assert!(error.contains("empty match_id"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_game_starting_message_with_oversized_match_id() {
+        let body = serde_json::json!({ "match_id": "m".repeat(game_pool::MAX_MATCH_ID_LEN + 1) });
+        let error = rejection(body.to_string().as_bytes());
+        assert!(error.contains("match_id is 256 bytes long"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_game_starting_message_with_non_string_player() {
+        let error = rejection(br#"{"match_id": "match-1", "players": ["alice", 7]}"#);
+        assert!(
+            error.contains("Malformed GameStarting message: invalid type"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn rejects_game_starting_message_with_empty_player_name() {
+        let error = rejection(br#"{"match_id": "match-1", "players": ["alice", ""]}"#);
+        assert!(error.contains("player 1 has an empty name"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_game_starting_message_with_oversized_player_name() {
+        let name = "p".repeat(controllers::MAX_PLAYER_NAME_LEN + 1);
+        let body = serde_json::json!({ "match_id": "match-1", "players": [name] });
+        let error = rejection(body.to_string().as_bytes());
+        assert!(
+            error.contains("player 0 name is 65 bytes long"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn rejects_game_starting_message_with_unknown_ruleset() {
+        let error = rejection(br#"{"match_id": "match-1", "ruleset": "riichi-3p"}"#);
+        assert!(error.contains("unknown variant `riichi-3p`"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_game_starting_message_with_invalid_utf8() {
+        rejection(b"{\"match_id\": \"match-\xff\"}");
+    }
+
+    #[tokio::test]
+    async fn queue_batch_runs_matches_concurrently() {
+        let transport = Arc::new(InMemoryTransport::default());
+        let topics = Topics {
+            incoming: "game.starting".to_string(),
+            outgoing: "game.complete".to_string(),
+            state: "game.state".to_string(),
+            started: "game.started".to_string(),
+        };
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        let _responder = respond_to_matches(transport.clone(), &topics, most_in_flight.clone())
+            .await
+            .drop_guard();
+        let queue_client = QueueClient::with_transport(transport, topics);
+
+        queue_batch(
+            &queue_client,
+            9,
+            Some(3),
+            &["angry_discardo".to_string()],
+            "",
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+
+        let most_in_flight = most_in_flight.load(Ordering::SeqCst);
+        assert!(
+            (2..=3).contains(&most_in_flight),
+            "{} matches in flight",
+            most_in_flight
+        );
+    }
+}
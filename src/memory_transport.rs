@@ -0,0 +1,294 @@
+//! In-process message transport, standing in for the broker in tests
+
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::queue::QueueError;
+use crate::transport::{
+    DeliveryHandler, DeliveryInfo, Disposition, MessagePredicate, MessageTransport, PublishOptions,
+    REQUEUE_DELAY,
+};
+
+/// A message held by the in-memory transport
+struct StoredMessage {
+    routing_key: String,
+    content_type: &'static str,
+    correlation_id: Option<String>,
+    payload: Vec<u8>,
+}
+
+/// A queue of the in-memory transport. Consumers of the same queue
+/// share the receiver, so each message goes to only one of them.
+#[derive(Clone)]
+struct MemoryQueue {
+    sender: mpsc::UnboundedSender<StoredMessage>,
+    receiver: Arc<AsyncMutex<mpsc::UnboundedReceiver<StoredMessage>>>,
+}
+
+impl MemoryQueue {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: Arc::new(AsyncMutex::new(receiver)),
+        }
+    }
+
+    async fn recv(&self) -> Option<StoredMessage> {
+        self.receiver.lock().await.recv().await
+    }
+}
+
+struct Binding {
+    exchange: String,
+    routing_key: String,
+    queue: String,
+}
+
+impl Binding {
+    fn matches(&self, exchange: &str, routing_key: &str) -> bool {
+        let pattern: Vec<&str> = self.routing_key.split('.').collect();
+        let words: Vec<&str> = routing_key.split('.').collect();
+        self.exchange == exchange && topic_matches(&pattern, &words)
+    }
+}
+
+/// Match routing key words against a topic pattern, where `*` stands for
+/// exactly one word and `#` for zero or more words
+fn topic_matches(pattern: &[&str], words: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => words.is_empty(),
+        Some((&"#", rest)) => (0..=words.len()).any(|skip| topic_matches(rest, &words[skip..])),
+        Some((&first, rest)) => match words.split_first() {
+            Some((&word, remaining)) => {
+                (first == "*" || first == word) && topic_matches(rest, remaining)
+            }
+            None => false,
+        },
+    }
+}
+
+#[derive(Default)]
+struct Routes {
+    queues: HashMap<String, MemoryQueue>,
+    bindings: Vec<Binding>,
+    /// Used to name the temporary queues of `consume_until`
+    next_temporary: u64,
+}
+
+impl Routes {
+    /// Get or create a queue, binding it to `exchange` under `routing_key`
+    fn bind(&mut self, exchange: &str, routing_key: &str, queue_name: &str) -> MemoryQueue {
+        let queue = self
+            .queues
+            .entry(queue_name.to_string())
+            .or_insert_with(MemoryQueue::new)
+            .clone();
+        let bound = self.bindings.iter().any(|b| {
+            b.exchange == exchange && b.routing_key == routing_key && b.queue == queue_name
+        });
+        if !bound {
+            self.bindings.push(Binding {
+                exchange: exchange.to_string(),
+                routing_key: routing_key.to_string(),
+                queue: queue_name.to_string(),
+            });
+        }
+        queue
+    }
+
+    fn delete(&mut self, queue_name: &str) {
+        self.queues.remove(queue_name);
+        self.bindings.retain(|b| b.queue != queue_name);
+    }
+}
+
+/// Transport routing messages between tasks of this process, for testing
+/// the queue client and game pool without a broker. Like AMQP, messages
+/// published to an exchange no queue is bound to are dropped.
+#[derive(Default)]
+pub struct InMemoryTransport {
+    routes: Mutex<Routes>,
+    consuming: AtomicBool,
+    /// Cancelled when the connection is replaced by `reconnect`
+    connection: Mutex<CancellationToken>,
+    /// Consumer tags, recorded each time a consumer starts on a connection
+    registrations: Mutex<Vec<String>>,
+}
+
+impl InMemoryTransport {
+    /// Consumer tags in the order consumers started, once for every
+    /// connection each of them consumed on
+    pub fn consumer_registrations(&self) -> Vec<String> {
+        self.registrations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Register a consumer on the current connection, returning the token
+    /// cancelled when that connection is replaced
+    fn register_consumer(&self, consumer_tag: &str) -> CancellationToken {
+        self.registrations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(consumer_tag.to_string());
+        self.connection
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+#[async_trait]
+impl MessageTransport for InMemoryTransport {
+    async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        options: PublishOptions<'_>,
+    ) -> Result<(), QueueError> {
+        let routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        // A queue bound more than once still receives a single copy
+        let targets: HashSet<&str> = routes
+            .bindings
+            .iter()
+            .filter(|b| b.matches(exchange, routing_key))
+            .map(|b| b.queue.as_str())
+            .collect();
+        for queue_name in targets {
+            if let Some(queue) = routes.queues.get(queue_name) {
+                // The queue keeps its own receiver alive, so this cannot fail
+                let _ = queue.sender.send(StoredMessage {
+                    routing_key: routing_key.to_string(),
+                    content_type: options.content_type,
+                    correlation_id: options.correlation_id.map(str::to_string),
+                    payload: payload.to_vec(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn consume(
+        &self,
+        exchange: &str,
+        queue_name: &str,
+        binding_key: &str,
+        handler: &DeliveryHandler,
+        shutdown: CancellationToken,
+    ) -> Result<(), QueueError> {
+        let queue = self.routes.lock().unwrap_or_else(|e| e.into_inner()).bind(
+            exchange,
+            binding_key,
+            queue_name,
+        );
+
+        let consumer_tag = format!("memory.{}", Uuid::new_v4());
+        let mut connection = self.register_consumer(&consumer_tag);
+        self.consuming.store(true, Ordering::SeqCst);
+        loop {
+            let message = tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => None,
+                _ = connection.cancelled() => {
+                    // Like a broker consumer, resume on the new connection under the same tag
+                    info!("Connection replaced, resuming consumer {}", consumer_tag);
+                    connection = self.register_consumer(&consumer_tag);
+                    continue;
+                }
+                message = queue.recv() => message,
+            };
+            let Some(message) = message else {
+                self.consuming.store(false, Ordering::SeqCst);
+                return Ok(());
+            };
+            let info = DeliveryInfo {
+                routing_key: &message.routing_key,
+                content_type: Some(message.content_type),
+                correlation_id: message.correlation_id.as_deref(),
+            };
+            match handler(&info, &message.payload) {
+                Ok(Disposition::Ack) => {}
+                Ok(Disposition::Requeue) => {
+                    tokio::time::sleep(REQUEUE_DELAY).await;
+                    // The queue keeps its own receiver alive, so this cannot fail
+                    let _ = queue.sender.send(message);
+                }
+                Err(e) => warn!("Dropping message rejected by handler: {}", e),
+            }
+        }
+    }
+
+    async fn consume_until(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        timeout: Duration,
+        predicate: &MessagePredicate<'_>,
+    ) -> Result<Vec<u8>, QueueError> {
+        let (queue_name, queue) = {
+            let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+            routes.next_temporary += 1;
+            let queue_name = format!("temporary-{}", routes.next_temporary);
+            let queue = routes.bind(exchange, routing_key, &queue_name);
+            (queue_name, queue)
+        };
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let received = loop {
+            match tokio::time::timeout_at(deadline, queue.recv()).await {
+                Ok(Some(message)) if !predicate(&message.payload) => continue,
+                received => break received,
+            }
+        };
+        self.routes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .delete(&queue_name);
+
+        match received {
+            Ok(Some(message)) => Ok(message.payload),
+            Ok(None) => Err(QueueError::NoMessage),
+            Err(_) => Err(QueueError::Timeout(timeout)),
+        }
+    }
+
+    fn is_consuming(&self) -> bool {
+        self.consuming.load(Ordering::SeqCst)
+    }
+
+    async fn check_ready(
+        &self,
+        exchange: &str,
+        queue_name: &str,
+        binding_key: &str,
+    ) -> Result<(), QueueError> {
+        self.routes.lock().unwrap_or_else(|e| e.into_inner()).bind(
+            exchange,
+            binding_key,
+            queue_name,
+        );
+        Ok(())
+    }
+
+    /// Queues stay as they are, running consumers resume on the new connection
+    async fn reconnect(&self) -> Result<(), QueueError> {
+        let replaced =
+            std::mem::take(&mut *self.connection.lock().unwrap_or_else(|e| e.into_inner()));
+        replaced.cancel();
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<(), QueueError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+//! Prometheus metrics for games and queue activity
+
+use anyhow::{anyhow, Result};
+use axum::{routing::get, Router};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::time::Duration;
+use tracing::info;
+
+const GAMES_STARTED: &str = "gametable_games_started_total";
+const GAMES_COMPLETED: &str = "gametable_games_completed_total";
+const GAMES_ERRORED: &str = "gametable_games_errored_total";
+const GAMES_ACTIVE: &str = "gametable_games_active";
+const GAMES_PENDING: &str = "gametable_games_pending";
+const GAME_DURATION: &str = "gametable_game_duration_seconds";
+const MESSAGES_CONSUMED: &str = "gametable_messages_consumed_total";
+const MESSAGES_PUBLISHED: &str = "gametable_messages_published_total";
+
+/// Histogram buckets for game durations, in seconds
+const GAME_DURATION_BUCKETS: &[f64] = &[1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+
+/// Install the global Prometheus recorder, returning a handle used to render it
+pub fn install() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(GAME_DURATION.to_string()),
+            GAME_DURATION_BUCKETS,
+        )?
+        .install_recorder()
+        .map_err(|e| anyhow!("Failed to install metrics recorder: {}", e))?;
+
+    ::metrics::describe_counter!(GAMES_STARTED, "Games started in the pool");
+    ::metrics::describe_counter!(GAMES_COMPLETED, "Games that ran to completion");
+    ::metrics::describe_counter!(
+        GAMES_ERRORED,
+        "Games that failed, timed out or could not start"
+    );
+    ::metrics::describe_gauge!(GAMES_ACTIVE, "Games currently running");
+    ::metrics::describe_gauge!(GAMES_PENDING, "Games waiting for a free slot");
+    ::metrics::describe_histogram!(
+        GAME_DURATION,
+        "Wall clock run time of finished games, by outcome"
+    );
+    ::metrics::describe_counter!(
+        MESSAGES_CONSUMED,
+        "GameStarting deliveries, by outcome: acked, requeued or rejected"
+    );
+    ::metrics::describe_counter!(
+        MESSAGES_PUBLISHED,
+        "Messages confirmed by the broker, by exchange"
+    );
+
+    Ok(handle)
+}
+
+/// Serve the rendered metrics at `/metrics` until the task is aborted
+pub async fn serve(port: u16, handle: PrometheusHandle) -> Result<()> {
+    let app = Router::new().route("/metrics", get(move || async move { handle.render() }));
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(|e| anyhow!("Failed to bind metrics port {}: {}", port, e))?;
+    info!("Serving metrics on port {}", port);
+
+    axum::serve(listener, app)
+        .await
+        .map_err(|e| anyhow!("Metrics server failed: {}", e))
+}
+
+pub fn game_started() {
+    ::metrics::counter!(GAMES_STARTED).increment(1);
+}
+
+pub fn game_completed(run: Duration) {
+    ::metrics::counter!(GAMES_COMPLETED).increment(1);
+    ::metrics::histogram!(GAME_DURATION, "outcome" => "completed").record(run.as_secs_f64());
+}
+
+/// Record a failed game, with its run time if it got as far as starting
+pub fn game_errored(run: Option<Duration>) {
+    ::metrics::counter!(GAMES_ERRORED).increment(1);
+    if let Some(run) = run {
+        ::metrics::histogram!(GAME_DURATION, "outcome" => "errored").record(run.as_secs_f64());
+    }
+}
+
+pub fn set_games(active: usize, pending: usize) {
+    ::metrics::gauge!(GAMES_ACTIVE).set(active as f64);
+    ::metrics::gauge!(GAMES_PENDING).set(pending as f64);
+}
+
+/// Record a consumed delivery, acknowledged or rejected
+pub fn message_consumed(acked: bool) {
+    let outcome = if acked { "acked" } else { "rejected" };
+    ::metrics::counter!(MESSAGES_CONSUMED, "outcome" => outcome).increment(1);
+}
+
+/// Record a delivery returned to the queue because it could not be taken yet
+pub fn message_requeued() {
+    ::metrics::counter!(MESSAGES_CONSUMED, "outcome" => "requeued").increment(1);
+}
+
+pub fn message_published(exchange: &str) {
+    ::metrics::counter!(MESSAGES_PUBLISHED, "exchange" => exchange.to_string()).increment(1);
+}
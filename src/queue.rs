@@ -1,148 +1,510 @@
 //! Queue management for handling game events using AMQP
 
-use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use futures_lite::stream::StreamExt;
 use lapin::{
-    options::*, types::FieldTable, BasicProperties, Channel, Connection, ConnectionProperties,
-    ExchangeKind,
+    message::Delivery,
+    options::*,
+    protocol::{AMQPErrorKind, AMQPHardError, AMQPSoftError},
+    types::{AMQPValue, FieldTable, LongString},
+    uri::AMQPUri,
+    BasicProperties, Channel, Connection, ExchangeKind, Queue,
 };
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tracing::{error, info};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
+use uuid::Uuid;
 
-struct QueueClientInner {
+use crate::config::{redact_url, Config};
+use crate::metrics;
+use crate::tls::{ConnectionSecurity, TlsError};
+use crate::transport::{
+    DeliveryHandler, DeliveryInfo, Disposition, MessagePredicate, MessageTransport, PublishOptions,
+    REQUEUE_DELAY,
+};
+
+/// Version of the GameComplete and GameStarted message schema, stamped on each
+/// message and its `schema_version` header. Bump it whenever their shape changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How long a failed publish waits on reconnecting before giving up,
+/// leaving further attempts to a background task
+const PUBLISH_RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Names of the exchanges the queue client publishes to and consumes from
+#[derive(Debug, Clone)]
+pub struct Topics {
+    /// Exchange GameStarting messages are published to
+    pub incoming: String,
+    /// Exchange GameComplete messages are published to
+    pub outgoing: String,
+    /// Exchange game state updates are streamed to
+    pub state: String,
+    /// Exchange GameStarted events are published to
+    pub started: String,
+}
+
+impl Topics {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            incoming: config.incoming_topic.clone(),
+            outgoing: config.outgoing_topic.clone(),
+            state: config.state_topic.clone(),
+            started: config.started_topic.clone(),
+        }
+    }
+}
+
+/// Failures talking to the queue cluster
+#[derive(Debug, Error)]
+pub enum QueueError {
+    #[error("Failed to connect to AMQP cluster: {0}")]
+    Connect(lapin::Error),
+    #[error("Invalid AMQP cluster URL: {0}")]
+    InvalidUrl(String),
+    #[error("Invalid TLS configuration: {0}")]
+    Tls(#[from] TlsError),
+    /// An AMQP operation failed on an established connection
+    #[error("Failed to {action}: {error}")]
+    Amqp {
+        action: &'static str,
+        error: lapin::Error,
+    },
+    #[error("Broker did not confirm the published message within {0:?}")]
+    ConfirmTimeout(Duration),
+    #[error("Broker rejected the published message")]
+    Nacked,
+    /// No message arrived within the time given to `QueueClient::consume_until`
+    #[error("No message received within {0:?}")]
+    Timeout(Duration),
+    #[error("No message received")]
+    NoMessage,
+    #[error("Unsupported content type: {0}")]
+    UnsupportedContentType(String),
+    #[error("Invalid JSON payload: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+    /// The queue already exists with other arguments, such as a different
+    /// dead-letter exchange, which the broker refuses to change in place
+    #[error("Queue {queue} exists with different arguments, delete it or configure it through a policy: {error}")]
+    IncompatibleQueue { queue: String, error: lapin::Error },
+}
+
+impl QueueError {
+    /// Whether the error should never be retried by reconnecting,
+    /// such as the broker refusing our credentials or vhost.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            QueueError::InvalidUrl(_) | QueueError::Tls(_) | QueueError::IncompatibleQueue { .. }
+        ) || matches!(
+            self.lapin_error(),
+            Some(lapin::Error::ProtocolError(e)) if matches!(
+                e.kind(),
+                AMQPErrorKind::Soft(AMQPSoftError::ACCESSREFUSED)
+                    | AMQPErrorKind::Hard(AMQPHardError::NOTALLOWED)
+            )
+        )
+    }
+
+    /// Whether the connection or channel failed, which reconnecting may fix,
+    /// as opposed to the broker answering with a rejection or the message being bad
+    pub fn is_connection_error(&self) -> bool {
+        self.lapin_error().is_some()
+    }
+
+    fn lapin_error(&self) -> Option<&lapin::Error> {
+        match self {
+            QueueError::Connect(error) | QueueError::Amqp { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Wrap a lapin error as a failure to perform `action`
+fn amqp(action: &'static str) -> impl FnOnce(lapin::Error) -> QueueError {
+    move |error| QueueError::Amqp { action, error }
+}
+
+/// Wire encoding of a message payload, advertised through its AMQP content type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Capnp,
+}
+
+impl Encoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Capnp => "application/capnp",
+        }
+    }
+
+    /// Messages without a content type are assumed to be JSON
+    fn from_content_type(content_type: Option<&str>) -> Result<Self, QueueError> {
+        match content_type {
+            None | Some("application/json") => Ok(Encoding::Json),
+            Some("application/capnp") => Ok(Encoding::Capnp),
+            Some(other) => Err(QueueError::UnsupportedContentType(other.to_string())),
+        }
+    }
+}
+
+/// A consumed message payload, decoded according to its content type
+#[derive(Debug)]
+pub enum IncomingMessage<'a> {
+    Json(serde_json::Value),
+    /// Raw Cap'n Proto data, until the spec crate schemas are wired in
+    Capnp(&'a [u8]),
+}
+
+impl<'a> IncomingMessage<'a> {
+    pub fn decode(content_type: Option<&str>, data: &'a [u8]) -> Result<Self, QueueError> {
+        match Encoding::from_content_type(content_type)? {
+            Encoding::Json => Ok(IncomingMessage::Json(serde_json::from_slice(data)?)),
+            Encoding::Capnp => Ok(IncomingMessage::Capnp(data)),
+        }
+    }
+}
+
+/// Exponential backoff between reconnection attempts
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max }
+    }
+
+    /// Delay before the given (zero based) retry attempt
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max)
+    }
+}
+
+/// A live connection and the channels opened on it.
+/// Replaced wholesale whenever the client reconnects.
+struct Link {
     connection: Connection,
     channel: Channel,
-    incoming_topic: String,
-    outgoing_topic: String,
+    /// Channels taken in turn by publishes, starting with `channel` itself
+    publish_channels: Vec<Channel>,
+    /// Incremented on every reconnect so concurrent failures only reconnect once
+    generation: u64,
 }
 
-/// Queue client for handling game-related messages
-#[derive(Clone)]
-pub struct QueueClient {
-    inner: Arc<QueueClientInner>,
+/// Opens links to the cluster and swaps in a new one when the current one fails.
+/// Shared with the background task retrying a reconnect a publish gave up on.
+struct Connector {
+    cluster_url: String,
+    security: ConnectionSecurity,
+    backoff: Backoff,
+    link: RwLock<Arc<Link>>,
+    /// Held for a single reconnect attempt, never while sleeping between attempts
+    reconnecting: Mutex<()>,
+    /// Whether a background task is retrying a reconnect
+    retrying: AtomicBool,
+    closing: AtomicBool,
+    /// Number of channels publishes are spread over
+    publish_channels: usize,
+    /// Exchanges declared on every (re)connect
+    exchanges: Vec<String>,
 }
 
-impl QueueClient {
-    /// Create a new queue client connected to the specified cluster URL
-    pub async fn new(cluster_url: &str) -> Result<Self> {
-        info!("Connecting to AMQP cluster at: {}", cluster_url);
+/// Transport over an AMQP cluster, reconnecting whenever the connection fails
+pub struct AmqpTransport {
+    connector: Arc<Connector>,
+    /// Whether the GameStarting consumer is taking deliveries on the current link
+    consuming: AtomicBool,
+    /// Counter picking the publish channel of the next publish
+    next_publish: AtomicUsize,
+    prefetch: u16,
+    dead_letter_exchange: Option<String>,
+    confirm_timeout: Duration,
+    /// Tag of the GameStarting consumer, unique to this instance
+    consumer_tag: String,
+}
 
-        let connection = Connection::connect(cluster_url, ConnectionProperties::default())
+impl Connector {
+    /// Connect, open `publish_channels` channels and declare the exchanges for topics
+    async fn open_link(
+        cluster_url: &str,
+        security: &ConnectionSecurity,
+        exchanges: &[String],
+        publish_channels: usize,
+        generation: u64,
+    ) -> Result<Link, QueueError> {
+        let connection = security
+            .connect(cluster_url)
             .await
-            .map_err(|e| anyhow!("Failed to connect to AMQP cluster: {}", e))?;
+            .map_err(QueueError::Connect)?;
+
+        let channel = Self::open_channel(&connection).await?;
+        let mut channels = vec![channel.clone()];
+        for _ in 1..publish_channels {
+            channels.push(Self::open_channel(&connection).await?);
+        }
+
+        for exchange in exchanges {
+            channel
+                .exchange_declare(
+                    exchange,
+                    ExchangeKind::Topic,
+                    ExchangeDeclareOptions::default(),
+                    FieldTable::default(),
+                )
+                .await
+                .map_err(amqp("declare exchange"))?;
+        }
 
+        Ok(Link {
+            connection,
+            channel,
+            publish_channels: channels,
+            generation,
+        })
+    }
+
+    /// Open a channel on which the broker confirms every publish
+    /// once it has taken responsibility for it
+    async fn open_channel(connection: &Connection) -> Result<Channel, QueueError> {
         let channel = connection
             .create_channel()
             .await
-            .map_err(|e| anyhow!("Failed to create AMQP channel: {}", e))?;
-
-        // Declare topics/exchanges
-        let incoming_topic = "game.starting".to_string();
-        let outgoing_topic = "game.complete".to_string();
-
-        // Declare exchanges for topics
+            .map_err(amqp("create AMQP channel"))?;
         channel
-            .exchange_declare(
-                &incoming_topic,
-                ExchangeKind::Topic,
-                ExchangeDeclareOptions::default(),
-                FieldTable::default(),
-            )
+            .confirm_select(ConfirmSelectOptions::default())
             .await
-            .map_err(|e| anyhow!("Failed to declare incoming exchange: {}", e))?;
+            .map_err(amqp("enable publisher confirms"))?;
+        Ok(channel)
+    }
 
-        channel
-            .exchange_declare(
-                &outgoing_topic,
-                ExchangeKind::Topic,
-                ExchangeDeclareOptions::default(),
-                FieldTable::default(),
-            )
-            .await
-            .map_err(|e| anyhow!("Failed to declare outgoing exchange: {}", e))?;
+    /// The current connection and channel
+    async fn link(&self) -> Arc<Link> {
+        self.link.read().await.clone()
+    }
 
-        let inner = QueueClientInner {
-            connection,
-            channel,
-            incoming_topic,
-            outgoing_topic,
-        };
+    /// Make one attempt at replacing `failed` with a new link, unless another
+    /// caller already did. The current link stays readable while connecting.
+    async fn reconnect_once(&self, failed: &Link) -> Result<(), QueueError> {
+        let _reconnecting = self.reconnecting.lock().await;
+        let current = self.link().await;
+        if current.generation != failed.generation {
+            // Another caller already reconnected while we waited for the lock
+            return Ok(());
+        }
+
+        // The old connection may still be up if only the channel failed
+        let _ = current.connection.close(200, "Reconnecting").await;
+
+        let new_link = Self::open_link(
+            &self.cluster_url,
+            &self.security,
+            &self.exchanges,
+            self.publish_channels,
+            current.generation + 1,
+        )
+        .await?;
+        *self.link.write().await = Arc::new(new_link);
+        info!("Reconnected to AMQP cluster");
+        Ok(())
+    }
+
+    /// Re-establish the connection and channel after `failed` stopped working,
+    /// retrying with exponential backoff. Authentication failures are not retried.
+    async fn reconnect_from(&self, failed: &Link) -> Result<(), QueueError> {
+        let mut attempt = 0;
+        while !self.closing.load(Ordering::SeqCst) {
+            let delay = self.backoff.delay(attempt);
+            warn!(
+                "Reconnecting to AMQP cluster in {:?} (attempt {})",
+                delay,
+                attempt + 1
+            );
+            tokio::time::sleep(delay).await;
+
+            match self.reconnect_once(failed).await {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is_fatal() => {
+                    error!("Giving up on reconnecting to AMQP cluster: {:#}", e);
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!("Failed to reconnect to AMQP cluster: {:#}", e);
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep trying to reconnect from `failed` in the background,
+    /// unless a background task already is
+    fn spawn_reconnect(self: &Arc<Self>, failed: Arc<Link>) {
+        if self.retrying.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let connector = self.clone();
+        tokio::spawn(async move {
+            // Failures are logged by reconnect_from
+            let _ = connector.reconnect_from(&failed).await;
+            connector.retrying.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+impl AmqpTransport {
+    /// Connect to the configured cluster URL, declaring `exchanges` as topic exchanges
+    pub async fn connect(config: &Config, exchanges: &[&str]) -> Result<Self, QueueError> {
+        let cluster_url = config.queue_cluster_url.trim().to_string();
+        // The URL carries credentials, so only the reason it is invalid is reported
+        let uri = cluster_url
+            .parse::<AMQPUri>()
+            .map_err(QueueError::InvalidUrl)?;
+        if uri.authority.host.is_empty() {
+            return Err(QueueError::InvalidUrl("no host given".to_string()));
+        }
+        info!(
+            "Connecting to AMQP cluster at: {}",
+            redact_url(&cluster_url)
+        );
+
+        let exchanges: Vec<String> = exchanges.iter().map(|e| e.to_string()).collect();
+        let security = ConnectionSecurity::from_config(config)?;
+        let publish_channels = config.publish_channels.max(1);
+        let link =
+            Connector::open_link(&cluster_url, &security, &exchanges, publish_channels, 0).await?;
 
         Ok(Self {
-            inner: Arc::new(inner),
+            connector: Arc::new(Connector {
+                cluster_url,
+                security,
+                backoff: Backoff::new(
+                    Duration::from_millis(config.reconnect_base_delay_ms),
+                    Duration::from_millis(config.reconnect_max_delay_ms),
+                ),
+                link: RwLock::new(Arc::new(link)),
+                reconnecting: Mutex::new(()),
+                retrying: AtomicBool::new(false),
+                closing: AtomicBool::new(false),
+                publish_channels,
+                exchanges,
+            }),
+            consuming: AtomicBool::new(false),
+            next_publish: AtomicUsize::new(0),
+            prefetch: config.prefetch,
+            dead_letter_exchange: config.dead_letter_exchange.clone(),
+            confirm_timeout: Duration::from_millis(config.publish_confirm_timeout_ms),
+            consumer_tag: Self::unique_consumer_tag(),
         })
     }
 
-    /// Start consuming messages from the GameStarting topic
-    /// The handler function will receive raw Cap'n Proto data for now
-    pub async fn start_consuming<F>(&self, queue_name: &str, handler: F) -> Result<()>
-    where
-        F: Fn(&[u8]) -> Result<()> + Send + Sync + 'static,
-    {
+    /// Consumer tag naming this host, made unique so that instances sharing
+    /// the incoming queue never collide when consuming it
+    fn unique_consumer_tag() -> String {
+        let host = hostname::get()
+            .map(|host| host.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "unknown-host".to_string());
+        format!("game_starting_consumer.{}.{}", host, Uuid::new_v4())
+    }
+
+    /// The channel the next publish on `link` goes out on
+    fn publish_channel<'a>(&self, link: &'a Link) -> &'a Channel {
+        let index = self.next_publish.fetch_add(1, Ordering::Relaxed);
+        &link.publish_channels[index % link.publish_channels.len()]
+    }
+
+    /// The current connection and channel
+    async fn link(&self) -> Arc<Link> {
+        self.connector.link().await
+    }
+
+    /// Consume from the queue on a single connection until its stream ends,
+    /// fails or `shutdown` is cancelled
+    async fn consume_link(
+        &self,
+        link: &Link,
+        exchange: &str,
+        queue_name: &str,
+        binding_key: &str,
+        handler: &DeliveryHandler,
+        shutdown: &CancellationToken,
+    ) -> Result<(), QueueError> {
         info!(
-            "Starting to consume messages from topic: {} on queue: {}",
-            self.inner.incoming_topic, queue_name
+            "Starting to consume messages from topic: {} on queue: {} bound with '{}'",
+            exchange, queue_name, binding_key
         );
 
-        // Declare a durable queue for consuming
         let queue = self
-            .inner
-            .channel
-            .queue_declare(
-                queue_name,
-                QueueDeclareOptions {
-                    durable: true,
-                    ..Default::default()
-                },
-                FieldTable::default(),
-            )
-            .await
-            .map_err(|e| anyhow!("Failed to declare queue: {}", e))?;
+            .declare_incoming_queue(&link.channel, exchange, queue_name, binding_key)
+            .await?;
 
-        // Bind the queue to the exchange
-        self.inner
-            .channel
-            .queue_bind(
-                &queue.name().as_str(),
-                &self.inner.incoming_topic,
-                "#",
-                QueueBindOptions::default(),
-                FieldTable::default(),
-            )
-            .await
-            .map_err(|e| anyhow!("Failed to bind queue to exchange: {}", e))?;
+        // Limit unacknowledged deliveries, 0 leaves the channel unlimited
+        if self.prefetch > 0 {
+            link.channel
+                .basic_qos(self.prefetch, BasicQosOptions::default())
+                .await
+                .map_err(amqp("set consumer prefetch"))?;
+        }
 
         // Start consuming
-        let mut consumer = self
-            .inner
+        let mut consumer = link
             .channel
             .basic_consume(
-                &queue.name().as_str(),
-                "game_starting_consumer",
+                queue.name().as_str(),
+                &self.consumer_tag,
                 BasicConsumeOptions::default(),
                 FieldTable::default(),
             )
             .await
-            .map_err(|e| anyhow!("Failed to start consuming: {}", e))?;
+            .map_err(amqp("start consuming"))?;
 
         // Handle messages using the consumer directly with StreamExt
         info!("Consumer started, waiting for messages...");
-        while let Some(delivery_result) = consumer.next().await {
+        self.consuming.store(true, Ordering::SeqCst);
+        loop {
+            // Only checked between deliveries, so the current one is always finished
+            let delivery_result = tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => {
+                    info!("Consumer shutting down, cancelling consumer");
+                    link.channel
+                        .basic_cancel(consumer.tag().as_str(), BasicCancelOptions::default())
+                        .await
+                        .map_err(amqp("cancel consumer"))?;
+                    return Ok(());
+                }
+                delivery_result = consumer.next() => match delivery_result {
+                    Some(delivery_result) => delivery_result,
+                    None => break,
+                },
+            };
+
             match delivery_result {
                 Ok(delivery) => {
-                    info!("Received GameStarting message");
-                    if let Err(e) = handler(&delivery.data) {
-                        error!("Error handling GameStarting message: {}", e);
-                    }
-
-                    // Acknowledge the message
-                    if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                        error!("Failed to acknowledge message: {}", e);
-                    }
+                    // The handler records the match id once it has decoded the message
+                    let span = info_span!(
+                        "delivery",
+                        delivery_tag = delivery.delivery_tag,
+                        correlation_id = tracing::field::Empty,
+                        match_id = tracing::field::Empty
+                    );
+                    Self::handle_delivery(delivery, handler)
+                        .instrument(span)
+                        .await;
                 }
                 Err(e) => {
                     error!("Error receiving message: {}", e);
-                    return Err(e.into());
+                    return Err(amqp("receive message")(e));
                 }
             }
         }
@@ -151,70 +513,342 @@ impl QueueClient {
         Ok(())
     }
 
-    /// Publish a GameStarting message to the incoming topic
-    pub async fn publish_game_starting(&self, game_starting_data: &[u8]) -> Result<()> {
-        info!("Publishing GameStarting message");
+    /// Pass a delivery to the handler, then acknowledge, requeue or reject it
+    async fn handle_delivery(delivery: Delivery, handler: &DeliveryHandler) {
+        info!("Received GameStarting message");
+        let info = DeliveryInfo {
+            routing_key: delivery.routing_key.as_str(),
+            content_type: delivery
+                .properties
+                .content_type()
+                .as_ref()
+                .map(|content_type| content_type.as_str()),
+            correlation_id: delivery
+                .properties
+                .correlation_id()
+                .as_ref()
+                .map(|correlation_id| correlation_id.as_str()),
+        };
+        if let Some(correlation_id) = info.correlation_id {
+            tracing::Span::current().record("correlation_id", correlation_id);
+        }
+        match handler(&info, &delivery.data) {
+            Ok(Disposition::Ack) => {
+                metrics::message_consumed(true);
+                // Acknowledge the message
+                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+                    error!("Failed to acknowledge message: {}", e);
+                }
+            }
+            Ok(Disposition::Requeue) => {
+                warn!(
+                    "GameStarting message cannot be taken yet, requeueing it in {:?}",
+                    REQUEUE_DELAY
+                );
+                metrics::message_requeued();
 
-        let properties = BasicProperties::default()
-            .with_content_type("application/capnp".into())
-            .with_delivery_mode(2); // Persistent
+                // Holding the delivery first keeps this consumer from taking it straight back
+                tokio::time::sleep(REQUEUE_DELAY).await;
+                let options = BasicNackOptions {
+                    requeue: true,
+                    ..Default::default()
+                };
+                if let Err(e) = delivery.nack(options).await {
+                    error!("Failed to requeue message: {}", e);
+                }
+            }
+            Err(e) => {
+                error!("Error handling GameStarting message: {}", e);
+                metrics::message_consumed(false);
 
-        self.inner
-            .channel
-            .basic_publish(
-                &self.inner.incoming_topic,
-                "",
-                BasicPublishOptions::default(),
-                game_starting_data,
-                properties,
+                // Reject without requeueing so it is dead-lettered
+                let options = BasicNackOptions {
+                    requeue: false,
+                    ..Default::default()
+                };
+                if let Err(e) = delivery.nack(options).await {
+                    error!("Failed to reject message: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Declare the incoming queue and bind it to `exchange` with `binding_key`,
+    /// along with its dead-letter exchange if configured
+    async fn declare_incoming_queue(
+        &self,
+        channel: &Channel,
+        exchange: &str,
+        queue_name: &str,
+        binding_key: &str,
+    ) -> Result<Queue, QueueError> {
+        // Route rejected messages to the dead-letter exchange, if configured
+        let mut queue_arguments = FieldTable::default();
+        if let Some(dead_letter_exchange) = &self.dead_letter_exchange {
+            Self::declare_dead_letter(channel, dead_letter_exchange, queue_name).await?;
+            queue_arguments.insert(
+                "x-dead-letter-exchange".into(),
+                AMQPValue::LongString(LongString::from(dead_letter_exchange.as_str())),
+            );
+        }
+
+        // Declare a durable queue for consuming
+        let queue = channel
+            .queue_declare(
+                queue_name,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                queue_arguments,
             )
             .await
-            .map_err(|e| anyhow!("Failed to publish GameStarting message: {}", e))?;
+            .map_err(|error| match &error {
+                lapin::Error::ProtocolError(e)
+                    if e.kind() == &AMQPErrorKind::Soft(AMQPSoftError::PRECONDITIONFAILED) =>
+                {
+                    QueueError::IncompatibleQueue {
+                        queue: queue_name.to_string(),
+                        error,
+                    }
+                }
+                _ => amqp("declare queue")(error),
+            })?;
+
+        // Bind the queue to the exchange
+        channel
+            .queue_bind(
+                queue.name().as_str(),
+                exchange,
+                binding_key,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .map_err(amqp("bind queue to exchange"))?;
+
+        Ok(queue)
+    }
+
+    /// Declare the dead-letter exchange along with a durable queue collecting
+    /// everything dead-lettered from `queue_name`, so rejected messages can be inspected
+    async fn declare_dead_letter(
+        channel: &Channel,
+        dead_letter_exchange: &str,
+        queue_name: &str,
+    ) -> Result<(), QueueError> {
+        channel
+            .exchange_declare(
+                dead_letter_exchange,
+                ExchangeKind::Topic,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .map_err(amqp("declare dead-letter exchange"))?;
+
+        let dead_letter_queue = format!("{}.dead-letter", queue_name);
+        channel
+            .queue_declare(
+                &dead_letter_queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .map_err(amqp("declare dead-letter queue"))?;
+
+        channel
+            .queue_bind(
+                &dead_letter_queue,
+                dead_letter_exchange,
+                "#",
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .map_err(amqp("bind dead-letter queue"))?;
 
-        info!("Successfully published GameStarting message");
         Ok(())
     }
 
-    /// Publish a GameComplete message to the outgoing topic
-    pub async fn publish_game_complete(
+    async fn publish_confirmed(
         &self,
+        channel: &Channel,
+        exchange: &str,
         routing_key: &str,
-        game_complete_data: &[u8],
-    ) -> Result<()> {
-        info!(
-            "Publishing GameComplete message with routing key: {}",
-            routing_key
-        );
-
-        let properties = BasicProperties::default()
-            .with_content_type("application/capnp".into())
-            .with_delivery_mode(2); // Persistent
-
-        self.inner
-            .channel
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), QueueError> {
+        let confirm = channel
             .basic_publish(
-                &self.inner.outgoing_topic,
+                exchange,
                 routing_key,
                 BasicPublishOptions::default(),
-                game_complete_data,
+                payload,
                 properties,
             )
             .await
-            .map_err(|e| anyhow!("Failed to publish GameComplete message: {}", e))?;
+            .map_err(amqp("publish message"))?;
 
-        info!("Successfully published GameComplete message");
+        let confirmation = tokio::time::timeout(self.confirm_timeout, confirm)
+            .await
+            .map_err(|_| QueueError::ConfirmTimeout(self.confirm_timeout))?
+            .map_err(amqp("confirm published message"))?;
+
+        if confirmation.is_nack() {
+            return Err(QueueError::Nacked);
+        }
+        metrics::message_published(exchange);
         Ok(())
     }
+}
 
-    /// Consume one message from a topic with a specific routing key
-    pub async fn consume_one(&self, topic: &str, routing_key: &str) -> Result<Vec<u8>> {
-        info!(
-            "Consuming one message from topic: {} with routing key: {}",
-            topic, routing_key
-        );
+#[async_trait]
+impl MessageTransport for AmqpTransport {
+    /// Publish to an exchange and wait for the broker to confirm it.
+    /// If the connection failed the publish is retried once after reconnecting,
+    /// a negative or missing confirmation is returned as an error straight away.
+    async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        options: PublishOptions<'_>,
+    ) -> Result<(), QueueError> {
+        let mut properties =
+            BasicProperties::default().with_content_type(options.content_type.into());
+        if options.persistent {
+            properties = properties.with_delivery_mode(2);
+        }
+        if let Some(correlation_id) = options.correlation_id {
+            properties = properties.with_correlation_id(correlation_id.into());
+        }
+        if let Some(schema_version) = options.schema_version {
+            let mut headers = FieldTable::default();
+            headers.insert("schema_version".into(), AMQPValue::LongUInt(schema_version));
+            properties = properties.with_headers(headers);
+        }
 
-        let queue = self
-            .inner
+        let link = self.link().await;
+        let error = match self
+            .publish_confirmed(
+                self.publish_channel(&link),
+                exchange,
+                routing_key,
+                payload,
+                properties.clone(),
+            )
+            .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let connector = &self.connector;
+        if !error.is_connection_error()
+            || error.is_fatal()
+            || connector.closing.load(Ordering::SeqCst)
+        {
+            return Err(error);
+        }
+
+        // A single bounded attempt, so the caller can spill the message
+        // rather than wait out an outage
+        warn!("Publish failed, reconnecting before retrying: {:#}", error);
+        match tokio::time::timeout(PUBLISH_RECONNECT_TIMEOUT, connector.reconnect_once(&link)).await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) if e.is_fatal() => return Err(e),
+            Ok(Err(e)) => {
+                warn!("Failed to reconnect to AMQP cluster: {:#}", e);
+                connector.spawn_reconnect(link);
+                return Err(error);
+            }
+            Err(_) => {
+                warn!(
+                    "Reconnecting took longer than {:?}, continuing in the background",
+                    PUBLISH_RECONNECT_TIMEOUT
+                );
+                connector.spawn_reconnect(link);
+                return Err(error);
+            }
+        }
+
+        let link = self.link().await;
+        self.publish_confirmed(
+            self.publish_channel(&link),
+            exchange,
+            routing_key,
+            payload,
+            properties,
+        )
+        .await
+    }
+
+    /// Connection and channel failures are retried by reconnecting,
+    /// so this only returns once the transport is closed, `shutdown` is cancelled
+    /// or reconnecting is hopeless.
+    ///
+    /// Once `shutdown` is cancelled no new deliveries are taken, the one being
+    /// handled is finished and acknowledged, and the consumer is cancelled on the broker.
+    async fn consume(
+        &self,
+        exchange: &str,
+        queue_name: &str,
+        binding_key: &str,
+        handler: &DeliveryHandler,
+        shutdown: CancellationToken,
+    ) -> Result<(), QueueError> {
+        loop {
+            let link = self.link().await;
+            let result = self
+                .consume_link(&link, exchange, queue_name, binding_key, handler, &shutdown)
+                .await;
+            self.consuming.store(false, Ordering::SeqCst);
+
+            if self.connector.closing.load(Ordering::SeqCst) || shutdown.is_cancelled() {
+                return result;
+            }
+
+            match result {
+                Ok(()) => warn!("Consumer stream ended unexpectedly, reconnecting"),
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) => warn!("Consumer failed, reconnecting: {:#}", e),
+            }
+
+            tokio::select! {
+                reconnected = self.connector.reconnect_from(&link) => reconnected?,
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    /// Close the current connection and open a new one. The consumer sees its
+    /// stream end and resumes on the new connection under the same consumer tag.
+    async fn reconnect(&self) -> Result<(), QueueError> {
+        let link = self.link().await;
+        self.connector.reconnect_once(&link).await
+    }
+
+    fn is_consuming(&self) -> bool {
+        self.consuming.load(Ordering::SeqCst)
+    }
+
+    async fn consume_until(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        timeout: Duration,
+        predicate: &MessagePredicate<'_>,
+    ) -> Result<Vec<u8>, QueueError> {
+        let link = self.link().await;
+        let queue = link
             .channel
             .queue_declare(
                 "",
@@ -225,51 +859,567 @@ impl QueueClient {
                 },
                 FieldTable::default(),
             )
-            .await?;
+            .await
+            .map_err(amqp("declare queue"))?;
+        let queue_name = queue.name().as_str();
+
+        // Concurrent calls share the channel, so each needs a consumer tag of its own
+        let consumer_tag = format!("consume_until.{}", Uuid::new_v4());
+        let mut consuming = false;
+        let result = async {
+            link.channel
+                .queue_bind(
+                    queue_name,
+                    exchange,
+                    routing_key,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await
+                .map_err(amqp("bind queue to exchange"))?;
+
+            let mut consumer = link
+                .channel
+                .basic_consume(
+                    queue_name,
+                    &consumer_tag,
+                    BasicConsumeOptions::default(),
+                    FieldTable::default(),
+                )
+                .await
+                .map_err(amqp("start consuming"))?;
+            consuming = true;
 
-        self.inner
+            let deadline = tokio::time::Instant::now() + timeout;
+            loop {
+                let Ok(next) = tokio::time::timeout_at(deadline, consumer.next()).await else {
+                    return Err(QueueError::Timeout(timeout));
+                };
+                let Some(delivery_result) = next else {
+                    return Err(QueueError::NoMessage);
+                };
+                let delivery = delivery_result.map_err(amqp("receive message"))?;
+                // The temporary queue holds a copy of its own, so messages that
+                // do not match are acknowledged without taking them from anyone
+                delivery
+                    .ack(BasicAckOptions::default())
+                    .await
+                    .map_err(amqp("acknowledge message"))?;
+                if predicate(&delivery.data) {
+                    return Ok(delivery.data);
+                }
+            }
+        }
+        .await;
+
+        // Nothing will read the temporary queue anymore, so remove it now
+        // rather than leaving it to the connection closing
+        if consuming {
+            if let Err(e) = link
+                .channel
+                .basic_cancel(&consumer_tag, BasicCancelOptions::default())
+                .await
+            {
+                warn!("Failed to cancel consumer {}: {}", consumer_tag, e);
+            }
+        }
+        if let Err(e) = link
             .channel
-            .queue_bind(
-                &queue.name().as_str(),
-                topic,
-                routing_key,
-                QueueBindOptions::default(),
+            .queue_delete(queue_name, QueueDeleteOptions::default())
+            .await
+        {
+            warn!("Failed to delete queue {}: {}", queue_name, e);
+        }
+        result
+    }
+
+    /// Passively declares the exchange, so a missing one is reported rather than created
+    async fn check_ready(
+        &self,
+        exchange: &str,
+        queue_name: &str,
+        binding_key: &str,
+    ) -> Result<(), QueueError> {
+        let link = self.link().await;
+
+        link.channel
+            .exchange_declare(
+                exchange,
+                ExchangeKind::Topic,
+                ExchangeDeclareOptions {
+                    passive: true,
+                    ..Default::default()
+                },
                 FieldTable::default(),
             )
+            .await
+            .map_err(amqp("find incoming exchange"))?;
+
+        self.declare_incoming_queue(&link.channel, exchange, queue_name, binding_key)
             .await?;
+        Ok(())
+    }
 
-        let consumer = self
-            .inner
-            .channel
-            .basic_consume(
-                &queue.name().as_str(),
-                "one_shot_consumer",
-                BasicConsumeOptions::default(),
-                FieldTable::default(),
+    async fn close(&self) -> Result<(), QueueError> {
+        info!("Closing AMQP connection");
+        self.connector.closing.store(true, Ordering::SeqCst);
+        self.link()
+            .await
+            .connection
+            .close(200, "Normal shutdown")
+            .await
+            .map_err(amqp("close AMQP connection"))
+    }
+}
+
+/// Queue client for handling game-related messages
+#[derive(Clone)]
+pub struct QueueClient {
+    transport: Arc<dyn MessageTransport>,
+    topics: Arc<Topics>,
+}
+
+impl QueueClient {
+    /// Create a new queue client connected to the configured cluster URL
+    pub async fn new(config: &Config) -> Result<Self, QueueError> {
+        let topics = Topics::from_config(config);
+        let exchanges = [
+            topics.incoming.as_str(),
+            topics.outgoing.as_str(),
+            topics.state.as_str(),
+            topics.started.as_str(),
+        ];
+        let transport = AmqpTransport::connect(config, &exchanges).await?;
+        Ok(Self::with_transport(Arc::new(transport), topics))
+    }
+
+    /// Create a queue client on top of any transport, such as
+    /// `InMemoryTransport` to test without a broker
+    pub fn with_transport(transport: Arc<dyn MessageTransport>, topics: Topics) -> Self {
+        Self {
+            transport,
+            topics: Arc::new(topics),
+        }
+    }
+
+    /// Start consuming messages from the GameStarting topic whose routing key
+    /// matches `binding_key`, a topic pattern such as `region.us.*` or `#` for all.
+    /// The handler function receives each message decoded according to its content type,
+    /// along with its routing key and correlation id, and decides whether it
+    /// is acknowledged or requeued to apply backpressure
+    ///
+    /// Messages the handler fails on are rejected. This only returns once the
+    /// transport gives up or `shutdown` is cancelled, see `MessageTransport::consume`.
+    pub async fn start_consuming<F>(
+        &self,
+        queue_name: &str,
+        binding_key: &str,
+        handler: F,
+        shutdown: CancellationToken,
+    ) -> Result<(), QueueError>
+    where
+        F: Fn(IncomingMessage, &DeliveryInfo<'_>) -> anyhow::Result<Disposition>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let handler = move |info: &DeliveryInfo<'_>, data: &[u8]| -> anyhow::Result<Disposition> {
+            handler(IncomingMessage::decode(info.content_type, data)?, info)
+        };
+        self.transport
+            .consume(
+                &self.topics.incoming,
+                queue_name,
+                binding_key,
+                &handler,
+                shutdown,
+            )
+            .await
+    }
+
+    /// Whether GameStarting messages are being consumed, false while
+    /// the connection is being re-established
+    pub fn is_consuming(&self) -> bool {
+        self.transport.is_consuming()
+    }
+
+    /// Verify the broker topology needed to consume games: the incoming exchange
+    /// exists and the incoming queue can be declared and bound to it
+    pub async fn check_ready(&self, queue_name: &str, binding_key: &str) -> Result<(), QueueError> {
+        self.transport
+            .check_ready(&self.topics.incoming, queue_name, binding_key)
+            .await
+    }
+
+    /// Publish a GameStarting message to the incoming topic under `routing_key`,
+    /// which decides the instances whose binding key takes the match
+    pub async fn publish_game_starting(
+        &self,
+        routing_key: &str,
+        game_starting_data: &[u8],
+        encoding: Encoding,
+        correlation_id: Option<&str>,
+    ) -> Result<(), QueueError> {
+        info!("Publishing GameStarting message");
+
+        let options = PublishOptions {
+            content_type: encoding.content_type(),
+            persistent: true,
+            correlation_id,
+            schema_version: None,
+        };
+        self.transport
+            .publish(
+                &self.topics.incoming,
+                routing_key,
+                game_starting_data,
+                options,
             )
             .await?;
 
-        let mut consumer_stream = consumer;
-        if let Some(delivery_result) = consumer_stream.next().await {
-            let delivery = delivery_result?;
-            delivery.ack(BasicAckOptions::default()).await?;
-            return Ok(delivery.data);
-        }
+        info!("Successfully published GameStarting message");
+        Ok(())
+    }
 
-        Err(anyhow!("No message received"))
+    /// Publish a GameComplete message to the outgoing topic
+    pub async fn publish_game_complete(
+        &self,
+        routing_key: &str,
+        game_complete_data: &[u8],
+        encoding: Encoding,
+        correlation_id: Option<&str>,
+    ) -> Result<(), QueueError> {
+        info!(
+            "Publishing GameComplete message with routing key: {}",
+            routing_key
+        );
+
+        let options = PublishOptions {
+            content_type: encoding.content_type(),
+            persistent: true,
+            correlation_id,
+            schema_version: Some(SCHEMA_VERSION),
+        };
+        self.transport
+            .publish(
+                &self.topics.outgoing,
+                routing_key,
+                game_complete_data,
+                options,
+            )
+            .await?;
+
+        info!("Successfully published GameComplete message");
+        Ok(())
+    }
+
+    /// Publish a GameStarted event to the started topic, keyed by match id
+    pub async fn publish_game_started(
+        &self,
+        routing_key: &str,
+        game_started_data: &[u8],
+        correlation_id: Option<&str>,
+    ) -> Result<(), QueueError> {
+        let options = PublishOptions {
+            content_type: Encoding::Json.content_type(),
+            persistent: true,
+            correlation_id,
+            schema_version: Some(SCHEMA_VERSION),
+        };
+        self.transport
+            .publish(
+                &self.topics.started,
+                routing_key,
+                game_started_data,
+                options,
+            )
+            .await
+    }
+
+    /// Publish a game state update to the state topic, keyed by match id.
+    /// Updates are transient, they are not worth persisting across broker restarts.
+    pub async fn publish_game_state(
+        &self,
+        routing_key: &str,
+        state_data: &[u8],
+    ) -> Result<(), QueueError> {
+        let options = PublishOptions {
+            content_type: Encoding::Json.content_type(),
+            persistent: false,
+            correlation_id: None,
+            schema_version: None,
+        };
+        self.transport
+            .publish(&self.topics.state, routing_key, state_data, options)
+            .await
+    }
+
+    /// Consume messages from a topic with the given routing key until one
+    /// satisfies `predicate`, returning its body
+    pub async fn consume_until<F>(
+        &self,
+        topic: &str,
+        routing_key: &str,
+        timeout: Duration,
+        predicate: F,
+    ) -> Result<Vec<u8>, QueueError>
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync,
+    {
+        info!(
+            "Consuming from topic: {} with routing key: {} until a message matches",
+            topic, routing_key
+        );
+        self.transport
+            .consume_until(topic, routing_key, timeout, &predicate)
+            .await
     }
 
     pub fn outgoing_topic(&self) -> &str {
-        &self.inner.outgoing_topic
+        &self.topics.outgoing
+    }
+
+    /// Replace the connection to the cluster with a fresh one, re-declaring
+    /// the exchanges. Every clone of the client uses the new connection, and
+    /// a running consumer resumes on it.
+    pub async fn reconnect(&self) -> Result<(), QueueError> {
+        self.transport.reconnect().await
     }
 
     /// Close the queue client connection
-    pub async fn close(&self) -> Result<()> {
-        info!("Closing AMQP connection");
-        self.inner
-            .connection
-            .close(200, "Normal shutdown")
+    pub async fn close(&self) -> Result<(), QueueError> {
+        self.transport.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_transport::InMemoryTransport;
+    use std::collections::HashSet;
+    use tokio::sync::mpsc;
+
+    /// Longest a test waits on a delivery before failing
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn test_config(vars: &[(&str, &str)]) -> Config {
+        let vars = [("WORKER_THREADS", "1")]
+            .iter()
+            .chain(vars)
+            .map(|(key, value)| (key.to_string(), value.to_string()));
+        envy::from_iter(vars).expect("valid test config")
+    }
+
+    /// Config for a broker at `TEST_AMQP_URL`, with exchanges and queue
+    /// of its own so that tests never see each other's messages
+    fn broker_config() -> Config {
+        let url = std::env::var("TEST_AMQP_URL").expect("TEST_AMQP_URL is set");
+        let prefix = format!("test.{}", Uuid::new_v4());
+        test_config(&[
+            ("QUEUE_CLUSTER_URL", &url),
+            ("INCOMING_QUEUE_NAME", &format!("{}.queue", prefix)),
+            ("INCOMING_TOPIC", &format!("{}.starting", prefix)),
+            ("OUTGOING_TOPIC", &format!("{}.complete", prefix)),
+            ("STATE_TOPIC", &format!("{}.state", prefix)),
+            ("STARTED_TOPIC", &format!("{}.started", prefix)),
+        ])
+    }
+
+    /// Have `consumers` compete for `queue_name`, publish `count` matches and
+    /// return the match ids each consumer took
+    async fn share_queue(
+        consumers: &[QueueClient],
+        publisher: &QueueClient,
+        queue_name: &str,
+        count: usize,
+    ) -> Vec<HashSet<String>> {
+        let (taken, mut deliveries) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        for (index, consumer) in consumers.iter().enumerate() {
+            // Declare the queue before publishing, so nothing is dropped
+            consumer.check_ready(queue_name, "#").await.unwrap();
+            let consumer = consumer.clone();
+            let taken = taken.clone();
+            let queue_name = queue_name.to_string();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let handler = move |message: IncomingMessage, _: &DeliveryInfo<'_>| {
+                    let IncomingMessage::Json(message) = message else {
+                        anyhow::bail!("unexpected Cap'n Proto message");
+                    };
+                    let _ = taken.send((index, message["match_id"].to_string()));
+                    Ok(Disposition::Ack)
+                };
+                consumer
+                    .start_consuming(&queue_name, "#", handler, shutdown)
+                    .await
+            });
+        }
+
+        for index in 0..count {
+            let body = serde_json::json!({ "match_id": format!("match-{}", index) });
+            publisher
+                .publish_game_starting("", body.to_string().as_bytes(), Encoding::Json, None)
+                .await
+                .unwrap();
+            // Let every consumer get back to waiting between publishes
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut taken_by = vec![HashSet::new(); consumers.len()];
+        for _ in 0..count {
+            let (index, match_id) = tokio::time::timeout(WAIT, deliveries.recv())
+                .await
+                .expect("not every message was delivered in time")
+                .unwrap();
+            assert!(taken_by[index].insert(match_id));
+        }
+        shutdown.cancel();
+        taken_by
+    }
+
+    fn assert_disjoint_cover(taken_by: &[HashSet<String>], count: usize) {
+        for taken in taken_by {
+            assert!(!taken.is_empty(), "a consumer took no messages");
+        }
+        let total: usize = taken_by.iter().map(HashSet::len).sum();
+        let all: HashSet<&String> = taken_by.iter().flatten().collect();
+        assert_eq!(total, count, "a message was delivered more than once");
+        assert_eq!(all.len(), count);
+    }
+
+    /// Have `client` consume `queue_name`, then publish a match before and after
+    /// reconnecting it, checking the consumer receives both
+    async fn consume_across_reconnect(client: &QueueClient, queue_name: &str) {
+        client.check_ready(queue_name, "#").await.unwrap();
+        let (taken, mut deliveries) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let consumer = client.clone();
+        let queue_name = queue_name.to_string();
+        let consumer_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let handler = move |message: IncomingMessage, _: &DeliveryInfo<'_>| {
+                let IncomingMessage::Json(message) = message else {
+                    anyhow::bail!("unexpected Cap'n Proto message");
+                };
+                let _ = taken.send(message["match_id"].as_str().unwrap_or_default().to_string());
+                Ok(Disposition::Ack)
+            };
+            consumer
+                .start_consuming(&queue_name, "#", handler, consumer_shutdown)
+                .await
+        });
+
+        for match_id in ["match-1", "match-2"] {
+            if match_id == "match-2" {
+                client.reconnect().await.unwrap();
+            }
+            let body = serde_json::json!({ "match_id": match_id });
+            client
+                .publish_game_starting("", body.to_string().as_bytes(), Encoding::Json, None)
+                .await
+                .unwrap();
+            let delivered = tokio::time::timeout(WAIT, deliveries.recv())
+                .await
+                .expect("message was not delivered in time")
+                .unwrap();
+            assert_eq!(delivered, match_id);
+        }
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn consumer_keeps_its_tag_across_reconnect() {
+        let config = test_config(&[("QUEUE_CLUSTER_URL", "amqp://localhost")]);
+        let transport = Arc::new(InMemoryTransport::default());
+        let client = QueueClient::with_transport(transport.clone(), Topics::from_config(&config));
+
+        consume_across_reconnect(&client, &config.incoming_queue_name).await;
+
+        let registrations = transport.consumer_registrations();
+        assert_eq!(registrations.len(), 2, "consumer did not resume once");
+        assert_eq!(registrations[0], registrations[1]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a broker at TEST_AMQP_URL"]
+    async fn broker_consumer_resumes_after_reconnect() {
+        let config = broker_config();
+        let client = QueueClient::new(&config).await.unwrap();
+
+        consume_across_reconnect(&client, &config.incoming_queue_name).await;
+    }
+
+    #[test]
+    fn consumer_tags_are_unique_per_instance() {
+        assert_ne!(
+            AmqpTransport::unique_consumer_tag(),
+            AmqpTransport::unique_consumer_tag()
+        );
+    }
+
+    #[tokio::test]
+    async fn consumers_of_the_same_queue_share_its_messages() {
+        let config = test_config(&[("QUEUE_CLUSTER_URL", "amqp://localhost")]);
+        let transport = Arc::new(InMemoryTransport::default());
+        let client =
+            || QueueClient::with_transport(transport.clone(), Topics::from_config(&config));
+
+        let taken_by = share_queue(
+            &[client(), client()],
+            &client(),
+            &config.incoming_queue_name,
+            20,
+        )
+        .await;
+        assert_disjoint_cover(&taken_by, 20);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a broker at TEST_AMQP_URL"]
+    async fn instances_sharing_a_broker_queue_share_its_messages() {
+        let config = broker_config();
+        let first = QueueClient::new(&config).await.unwrap();
+        let second = QueueClient::new(&config).await.unwrap();
+
+        let taken_by = share_queue(
+            &[first.clone(), second],
+            &first,
+            &config.incoming_queue_name,
+            20,
+        )
+        .await;
+        assert_disjoint_cover(&taken_by, 20);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a broker at TEST_AMQP_URL"]
+    async fn publish_succeeds_after_channel_error() {
+        let config = broker_config();
+        let topics = Topics::from_config(&config);
+        let transport = Arc::new(
+            AmqpTransport::connect(&config, &[topics.incoming.as_str()])
+                .await
+                .unwrap(),
+        );
+        let client = QueueClient::with_transport(transport.clone(), topics);
+        // Publishing to an exchange that does not exist makes the broker close the channel
+        let broken = QueueClient::with_transport(
+            transport,
+            Topics {
+                incoming: format!("{}.missing", config.incoming_topic),
+                ..Topics::from_config(&config)
+            },
+        );
+
+        let body = br#"{"match_id": "match-1"}"#;
+        assert!(broken
+            .publish_game_starting("", body, Encoding::Json, None)
+            .await
+            .is_err());
+        client
+            .publish_game_starting("", body, Encoding::Json, None)
             .await
-            .map_err(|e| anyhow!("Failed to close AMQP connection: {}", e))
+            .unwrap();
     }
 }
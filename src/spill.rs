@@ -0,0 +1,112 @@
+//! On-disk spill of GameComplete messages that could not be published
+//!
+//! Each message is kept in its own file, named so that listing the directory
+//! yields them oldest first.
+
+use chrono::Utc;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::files::{safe_file_stem, write_atomic};
+
+const SPILL_EXTENSION: &str = "json";
+
+/// A spilled GameComplete message
+pub struct SpilledCompletion {
+    pub path: PathBuf,
+    pub match_id: String,
+    pub correlation_id: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+/// Directory holding GameComplete messages waiting to be republished
+pub struct CompletionSpill {
+    dir: PathBuf,
+}
+
+impl CompletionSpill {
+    /// Use `dir` for spilled messages, creating it if needed
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Write a JSON GameComplete message, returning the file it was stored in
+    pub async fn store(&self, match_id: &str, payload: &[u8]) -> io::Result<PathBuf> {
+        let name = format!(
+            "{:020}-{}",
+            Utc::now().timestamp_micros(),
+            safe_file_stem(match_id)
+        );
+        let path = self.dir.join(format!("{}.{}", name, SPILL_EXTENSION));
+        write_atomic(&path, payload).await?;
+        Ok(path)
+    }
+
+    /// Spilled messages, oldest first. Files that cannot be read as a
+    /// GameComplete message are left in place and skipped.
+    pub async fn entries(&self) -> io::Result<Vec<SpilledCompletion>> {
+        let mut paths = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == SPILL_EXTENSION) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut entries = Vec::with_capacity(paths.len());
+        for path in paths {
+            let payload = tokio::fs::read(&path).await?;
+            let message = serde_json::from_slice::<serde_json::Value>(&payload).ok();
+            let match_id = message
+                .as_ref()
+                .and_then(|message| message["match_id"].as_str().map(str::to_string));
+            match match_id {
+                Some(match_id) => entries.push(SpilledCompletion {
+                    path,
+                    match_id,
+                    correlation_id: message
+                        .as_ref()
+                        .and_then(|message| message["correlation_id"].as_str().map(str::to_string)),
+                    payload,
+                }),
+                None => warn!(
+                    "Skipping spilled file {} without a match id",
+                    path.display()
+                ),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Delete a spilled message once it has been republished
+    pub async fn remove(&self, entry: &SpilledCompletion) -> io::Result<()> {
+        tokio::fs::remove_file(&entry.path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_message_with_longest_match_id() {
+        let dir = std::env::temp_dir().join(format!("gametable-spill-{}", uuid::Uuid::new_v4()));
+        let spill = CompletionSpill::new(&dir).unwrap();
+        let match_id = "m".repeat(255);
+        let payload = serde_json::to_vec(&serde_json::json!({ "match_id": match_id })).unwrap();
+
+        let path = spill.store(&match_id, &payload).await.unwrap();
+
+        let entries = spill.entries().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, path);
+        assert_eq!(entries[0].match_id, match_id);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+//! Checkpoints of running matches, so matches lost to a crash can be recovered
+//!
+//! A checkpoint is written when a match starts, refreshed periodically while it
+//! runs and removed once it finishes. Checkpoints still present when the pool
+//! starts belong to matches a previous run never finished.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::files::{safe_file_stem, write_atomic};
+use crate::game::Ruleset;
+
+const CHECKPOINT_EXTENSION: &str = "json";
+
+/// What is needed to run a match again from the start
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchCheckpoint {
+    pub match_id: String,
+    pub players: Vec<String>,
+    /// Names of the bot strategies seated in empty seats
+    pub fill_bots: Vec<String>,
+    pub seed: u64,
+    pub ruleset: Ruleset,
+    pub priority: i32,
+    pub attempt: u32,
+    pub routing_key: String,
+    pub idempotency_key: Option<String>,
+    pub correlation_id: Option<String>,
+    /// Steps the game had advanced when the checkpoint was taken
+    pub steps: u64,
+}
+
+/// Storage of match checkpoints, keyed by match id
+#[async_trait]
+pub trait MatchStore: Send + Sync {
+    /// Store a checkpoint, replacing any earlier one of the same match
+    async fn save(&self, checkpoint: &MatchCheckpoint) -> io::Result<()>;
+
+    /// Forget the checkpoint of a match, if there is one
+    async fn remove(&self, match_id: &str) -> io::Result<()>;
+
+    /// Every stored checkpoint
+    async fn load_all(&self) -> io::Result<Vec<MatchCheckpoint>>;
+}
+
+/// Checkpoints kept as JSON files in a local directory, one per match
+pub struct FileMatchStore {
+    dir: PathBuf,
+}
+
+impl FileMatchStore {
+    /// Use `dir` for checkpoints, creating it if needed
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// File holding the checkpoint of `match_id`
+    fn path(&self, match_id: &str) -> PathBuf {
+        self.dir.join(format!(
+            "{}.{}",
+            safe_file_stem(match_id),
+            CHECKPOINT_EXTENSION
+        ))
+    }
+}
+
+#[async_trait]
+impl MatchStore for FileMatchStore {
+    async fn save(&self, checkpoint: &MatchCheckpoint) -> io::Result<()> {
+        let path = self.path(&checkpoint.match_id);
+        write_atomic(&path, &serde_json::to_vec(checkpoint)?).await
+    }
+
+    async fn remove(&self, match_id: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(match_id)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Files that cannot be read as a checkpoint are left in place and skipped
+    async fn load_all(&self) -> io::Result<Vec<MatchCheckpoint>> {
+        let mut paths = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == CHECKPOINT_EXTENSION)
+            {
+                paths.push(path);
+            }
+        }
+
+        let mut checkpoints = Vec::with_capacity(paths.len());
+        for path in paths {
+            let data = tokio::fs::read(&path).await?;
+            match serde_json::from_slice(&data) {
+                Ok(checkpoint) => checkpoints.push(checkpoint),
+                Err(e) => warn!("Skipping unreadable checkpoint {}: {}", path.display(), e),
+            }
+        }
+        Ok(checkpoints)
+    }
+}
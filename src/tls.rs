@@ -0,0 +1,202 @@
+//! TLS for amqps connections to the queue cluster
+
+use lapin::{
+    tcp::{AMQPUriTcpExt, OwnedIdentity, OwnedTLSConfig, RustlsConnector},
+    uri::{AMQPScheme, AMQPUri},
+    Connection, ConnectionProperties,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::config::Config;
+
+/// TLS settings that cannot produce a working connection
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Failed to read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("No PEM certificates found in CA bundle {0}")]
+    InvalidCa(PathBuf),
+    #[error("No trusted root certificates: the system store is unavailable or empty ({0}), set TLS_CA_CERT")]
+    NoTrustRoots(String),
+}
+
+/// How connections to the queue cluster are secured
+pub enum ConnectionSecurity {
+    /// `amqp://` URL, no TLS
+    Plain,
+    /// The broker certificate is verified against `cert_chain`,
+    /// or the system roots when unset
+    Verified {
+        cert_chain: Option<String>,
+        /// PKCS#12 client identity and its password
+        identity: Option<(Vec<u8>, String)>,
+    },
+    /// Any broker certificate is accepted, for development brokers only
+    Insecure(RustlsConnector),
+}
+
+impl ConnectionSecurity {
+    /// Resolve the TLS settings for the configured cluster URL,
+    /// failing if an `amqps` URL would have nothing to trust
+    pub fn from_config(config: &Config) -> Result<Self, TlsError> {
+        let amqps = config
+            .queue_cluster_url
+            .trim()
+            .parse::<AMQPUri>()
+            .is_ok_and(|uri| uri.scheme == AMQPScheme::AMQPS);
+        if !amqps {
+            return Ok(Self::Plain);
+        }
+
+        if config.tls_skip_verify {
+            warn!("TLS_SKIP_VERIFY is set, the queue cluster certificate will not be verified");
+            return Ok(Self::Insecure(insecure_connector()));
+        }
+
+        let cert_chain = match &config.tls_ca_cert {
+            Some(path) => {
+                let pem = read_to_string(path)?;
+                let certs = rustls_pemfile::certs(&mut pem.as_bytes())
+                    .filter_map(Result::ok)
+                    .count();
+                if certs == 0 {
+                    return Err(TlsError::InvalidCa(path.clone()));
+                }
+                Some(pem)
+            }
+            None => {
+                // The engine panics on an unusable system store, so check it up front
+                match rustls_native_certs::load_native_certs() {
+                    Ok(roots) if !roots.is_empty() => None,
+                    Ok(_) => return Err(TlsError::NoTrustRoots("no certificates".to_string())),
+                    Err(e) => return Err(TlsError::NoTrustRoots(e.to_string())),
+                }
+            }
+        };
+
+        let identity = match &config.tls_client_identity {
+            Some(path) => {
+                let der = std::fs::read(path).map_err(|source| TlsError::Read {
+                    path: path.clone(),
+                    source,
+                })?;
+                let password = config
+                    .tls_client_identity_password
+                    .clone()
+                    .unwrap_or_default();
+                Some((der, password))
+            }
+            None => None,
+        };
+
+        Ok(Self::Verified {
+            cert_chain,
+            identity,
+        })
+    }
+
+    /// Open a connection to `url` secured as configured
+    // The handshake error type is imposed by the engine's connector hook
+    #[allow(clippy::result_large_err)]
+    pub async fn connect(&self, url: &str) -> lapin::Result<Connection> {
+        let properties = ConnectionProperties::default();
+        match self {
+            Self::Plain => Connection::connect(url, properties).await,
+            Self::Verified {
+                cert_chain,
+                identity,
+            } => {
+                let config = OwnedTLSConfig {
+                    identity: identity.as_ref().map(|(der, password)| OwnedIdentity {
+                        der: der.clone(),
+                        password: password.clone(),
+                    }),
+                    cert_chain: cert_chain.clone(),
+                };
+                Connection::connect_with_config(url, properties, config).await
+            }
+            Self::Insecure(connector) => {
+                let uri = url
+                    .parse::<AMQPUri>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let connector = connector.clone();
+                let connect = move |uri: &AMQPUri| {
+                    // Open the TCP stream as plain AMQP and run the handshake ourselves
+                    let plain = AMQPUri {
+                        scheme: AMQPScheme::AMQP,
+                        ..uri.clone()
+                    };
+                    plain
+                        .connect()
+                        .and_then(|stream| stream.into_rustls(&connector, &uri.authority.host))
+                };
+                Connection::connector(uri, Box::new(connect), properties).await
+            }
+        }
+    }
+}
+
+fn read_to_string(path: &Path) -> Result<String, TlsError> {
+    std::fs::read_to_string(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Connector accepting any server certificate, still checking handshake signatures
+fn insecure_connector() -> RustlsConnector {
+    let algorithms = rustls::crypto::ring::default_provider().signature_verification_algorithms;
+    ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(algorithms)))
+        .with_no_client_auth()
+        .into()
+}
+
+#[derive(Debug)]
+struct AcceptAnyCertificate(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
@@ -0,0 +1,103 @@
+//! Message transports the queue client publishes and consumes through
+
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::queue::QueueError;
+
+/// How long a consumer holds a delivery it could not take yet before returning
+/// it to the queue, so a busy consumer slows down rather than spinning on redeliveries
+pub const REQUEUE_DELAY: Duration = Duration::from_secs(1);
+
+/// What to do with a delivery the handler did not fail on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// The message was handled, acknowledge it
+    Ack,
+    /// The message cannot be taken right now, return it to the queue for later
+    Requeue,
+}
+
+/// Properties of a consumed message
+#[derive(Debug, Clone, Copy)]
+pub struct DeliveryInfo<'a> {
+    pub routing_key: &'a str,
+    pub content_type: Option<&'a str>,
+    /// Id tying the message to the request that caused it, for tracing across services
+    pub correlation_id: Option<&'a str>,
+}
+
+/// Called with the properties and body of every consumed message.
+/// Returning an error rejects the message.
+pub type DeliveryHandler =
+    dyn Fn(&DeliveryInfo<'_>, &[u8]) -> anyhow::Result<Disposition> + Send + Sync;
+
+/// Decides whether a consumed message body is the one waited for
+pub type MessagePredicate<'a> = dyn Fn(&[u8]) -> bool + Send + Sync + 'a;
+
+/// How a published message is delivered
+#[derive(Debug, Clone, Copy)]
+pub struct PublishOptions<'a> {
+    pub content_type: &'static str,
+    /// Whether the message should survive a broker restart
+    pub persistent: bool,
+    /// Id tying the message to the request that caused it
+    pub correlation_id: Option<&'a str>,
+    /// Version of the message schema, sent as the `schema_version` header
+    pub schema_version: Option<u32>,
+}
+
+/// Topic based publish/consume, as offered by an AMQP broker
+#[async_trait]
+pub trait MessageTransport: Send + Sync {
+    /// Publish a message to an exchange under a routing key
+    async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        options: PublishOptions<'_>,
+    ) -> Result<(), QueueError>;
+
+    /// Bind `queue_name` to `exchange` with the `binding_key` topic pattern and
+    /// hand each delivery to `handler` until `shutdown` is cancelled. Deliveries
+    /// are acknowledged or requeued as the handler decides, and rejected when it fails.
+    async fn consume(
+        &self,
+        exchange: &str,
+        queue_name: &str,
+        binding_key: &str,
+        handler: &DeliveryHandler,
+        shutdown: CancellationToken,
+    ) -> Result<(), QueueError>;
+
+    /// Wait for the first message on `exchange` matching `routing_key` whose
+    /// body satisfies `predicate`, discarding the others, failing with
+    /// `QueueError::Timeout` if none arrives within `timeout`
+    async fn consume_until(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        timeout: Duration,
+        predicate: &MessagePredicate<'_>,
+    ) -> Result<Vec<u8>, QueueError>;
+
+    /// Whether `consume` is currently taking deliveries, false while it
+    /// is reconnecting or once it has stopped
+    fn is_consuming(&self) -> bool;
+
+    /// Verify that `queue_name` could be consumed from `exchange`
+    async fn check_ready(
+        &self,
+        exchange: &str,
+        queue_name: &str,
+        binding_key: &str,
+    ) -> Result<(), QueueError>;
+
+    /// Drop the current connection and establish a new one, restoring
+    /// exchanges and any running consumer on it
+    async fn reconnect(&self) -> Result<(), QueueError>;
+
+    async fn close(&self) -> Result<(), QueueError>;
+}
@@ -0,0 +1,572 @@
+//! Fixed pool of worker threads stepping many games each
+//!
+//! Libmahjong game states are driven through synchronous FFI calls, so games
+//! run on plain OS threads rather than the async runtime. Each worker owns the
+//! games assigned to it for their whole lifetime and advances them one step at
+//! a time in turn, so a handful of threads can serve any number of games.
+
+use libmahjong_rs::observe::StateFunctionType;
+use std::any::Any;
+use std::ops::Rem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, warn, Span};
+
+use crate::controllers::GameController;
+use crate::game::{AdvanceOutcome, GameError, GameMatch, HistoryRetention, Ruleset, StateSnapshot};
+use crate::game_pool::{GameResult, GameStatus, PoolError, StateUpdate, TurnLog};
+
+/// A game handed to a worker to create and run to completion
+pub struct GameJob {
+    pub match_id: String,
+    pub controllers: [GameController; 4],
+    pub seed: u64,
+    pub ruleset: Ruleset,
+    /// Asks the worker to stop the game at its next step
+    pub cancelled: Arc<AtomicBool>,
+    /// Counts the steps the game advanced by
+    pub steps: Arc<AtomicU64>,
+    /// Receives the final status of the game
+    pub status_tx: mpsc::Sender<GameStatus>,
+    /// Receives every observed state of the game, when streaming is enabled
+    pub states: Option<broadcast::Sender<StateUpdate>>,
+    /// How much turn history to attach to the result, none when unset
+    pub history: Option<HistoryRetention>,
+    /// Span the game runs in, entered by the worker whenever it works on the game
+    pub span: Span,
+}
+
+/// Creates the games workers run, libmahjong games unless the pool is
+/// given another runner, such as `ScriptedRunner` to run without the engine
+pub trait GameRunner: Send + Sync {
+    /// Create the game for a job, on the worker thread that will step it
+    fn create(&self, job: GameJob) -> Result<Box<dyn SteppedGame>, GameError>;
+}
+
+/// A game created by a `GameRunner`, stepped by its worker until done
+pub trait SteppedGame {
+    /// Advance the game by one step
+    fn step(&mut self) -> Step;
+}
+
+/// Runs games on the libmahjong engine
+pub struct EngineRunner;
+
+impl GameRunner for EngineRunner {
+    fn create(&self, job: GameJob) -> Result<Box<dyn SteppedGame>, GameError> {
+        let seats: Vec<String> = job.controllers.iter().map(|c| c.to_string()).collect();
+        let mut game_match =
+            GameMatch::try_new(job.match_id.clone(), job.controllers, job.seed, job.ruleset)?;
+        if let Some(retention) = job.history {
+            game_match.record_history(retention);
+        }
+        if let Some(states) = job.states {
+            let match_id = job.match_id.clone();
+            let mut step = 0;
+            game_match.on_advance(move |observed| {
+                step += 1;
+                // Having no subscribers at the moment is fine
+                let _ = states.send(StateUpdate {
+                    match_id: match_id.clone(),
+                    step,
+                    state: StateSnapshot::from(observed),
+                });
+            });
+        }
+        Ok(Box::new(EngineGame {
+            match_id: job.match_id,
+            game_match,
+            seats,
+            total_rounds: 0,
+            last_observed: None,
+        }))
+    }
+}
+
+/// Runner playing no actual game: each game progresses for `steps` steps,
+/// then ends with `status`, so the pool can be driven deterministically
+#[cfg(test)]
+pub struct ScriptedRunner {
+    pub steps: u64,
+    pub status: GameStatus,
+    /// Number of games created so far
+    pub created: AtomicUsize,
+}
+
+#[cfg(test)]
+impl ScriptedRunner {
+    pub fn new(steps: u64, status: GameStatus) -> Self {
+        Self {
+            steps,
+            status,
+            created: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+impl GameRunner for ScriptedRunner {
+    fn create(&self, _job: GameJob) -> Result<Box<dyn SteppedGame>, GameError> {
+        self.created.fetch_add(1, Ordering::Relaxed);
+        Ok(Box::new(ScriptedGame {
+            remaining: self.steps,
+            status: Some(self.status.clone()),
+        }))
+    }
+}
+
+#[cfg(test)]
+struct ScriptedGame {
+    remaining: u64,
+    status: Option<GameStatus>,
+}
+
+#[cfg(test)]
+impl SteppedGame for ScriptedGame {
+    fn step(&mut self) -> Step {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            return Step::Progressed;
+        }
+        match self.status.take() {
+            Some(status) => Step::Done(status),
+            None => Step::Done(GameStatus::Error("Game already ended".to_string())),
+        }
+    }
+}
+
+/// Something for a worker thread to act on
+enum WorkerEvent {
+    /// Take on a new game
+    Job(Box<GameJob>),
+    /// Step the games waiting on input again, one of them may be able to go on
+    Wake,
+}
+
+/// Wakes the worker running a game, so that a game waiting on input
+/// notices it can go on or was cancelled
+#[derive(Clone)]
+pub struct Waker {
+    events: std_mpsc::Sender<WorkerEvent>,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        // A worker that has exited has no games left to wake
+        let _ = self.events.send(WorkerEvent::Wake);
+    }
+}
+
+/// Handle to a worker thread
+struct Worker {
+    events: std_mpsc::Sender<WorkerEvent>,
+    /// Games currently assigned to the worker
+    load: Arc<AtomicUsize>,
+}
+
+/// Fixed size pool of threads running games
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+}
+
+impl WorkerPool {
+    /// Spawn `threads` worker threads, at least one, creating games with `runner`.
+    /// Workers exit once the pool and the wakers of their games are dropped
+    /// and their games have finished.
+    pub fn new(threads: usize, runner: Arc<dyn GameRunner>) -> Result<Self, PoolError> {
+        let workers = (0..threads.max(1))
+            .map(|index| {
+                let (events, event_rx) = std_mpsc::channel();
+                let load = Arc::new(AtomicUsize::new(0));
+                let worker_load = load.clone();
+                let runner = runner.clone();
+                thread::Builder::new()
+                    .name(format!("game-worker-{}", index))
+                    .spawn(move || run_worker(event_rx, worker_load, runner))
+                    .map_err(|error| PoolError::WorkerSpawn { index, error })?;
+                Ok(Worker { events, load })
+            })
+            .collect::<Result<Vec<_>, PoolError>>()?;
+
+        info!("Started {} game worker threads", workers.len());
+        Ok(Self { workers })
+    }
+
+    /// Assign a game to the least loaded worker, returning the waker of that worker
+    pub fn submit(&self, job: GameJob) -> Result<Waker, PoolError> {
+        let Some(worker) = self
+            .workers
+            .iter()
+            .min_by_key(|worker| worker.load.load(Ordering::Relaxed))
+        else {
+            return Err(PoolError::WorkersStopped(job.match_id));
+        };
+
+        worker.load.fetch_add(1, Ordering::Relaxed);
+        let match_id = job.match_id.clone();
+        match worker.events.send(WorkerEvent::Job(Box::new(job))) {
+            Ok(()) => Ok(Waker {
+                events: worker.events.clone(),
+            }),
+            Err(_) => {
+                worker.load.fetch_sub(1, Ordering::Relaxed);
+                Err(PoolError::WorkersStopped(match_id))
+            }
+        }
+    }
+}
+
+/// What happened to a game when a worker stepped it
+pub enum Step {
+    /// The game advanced and can be stepped again right away
+    Progressed,
+    /// The game is waiting on one of its controllers, and is stepped
+    /// again once its worker is woken
+    Waiting,
+    /// The game is over, with its final status
+    Done(GameStatus),
+}
+
+/// A game owned by a worker, along with where to report its end
+struct RunningGame {
+    match_id: String,
+    game: Box<dyn SteppedGame>,
+    cancelled: Arc<AtomicBool>,
+    steps: Arc<AtomicU64>,
+    status_tx: mpsc::Sender<GameStatus>,
+    span: Span,
+}
+
+impl RunningGame {
+    /// Create the game for a job, reporting the failure if it cannot be created
+    fn start(runner: &dyn GameRunner, job: GameJob) -> Option<Self> {
+        let span = job.span.clone();
+        let _entered = span.enter();
+        info!("Game worker starting match: {}", job.match_id);
+
+        let match_id = job.match_id.clone();
+        let cancelled = job.cancelled.clone();
+        let steps = job.steps.clone();
+        let status_tx = job.status_tx.clone();
+        let created = panic::catch_unwind(AssertUnwindSafe(|| runner.create(job)))
+            .unwrap_or_else(|payload| Err(GameError::Panicked(panic_message(payload.as_ref()))));
+        match created {
+            Ok(game) => Some(Self {
+                match_id,
+                game,
+                cancelled,
+                steps,
+                status_tx,
+                span: span.clone(),
+            }),
+            Err(e) => {
+                error!("Failed to create game match {}: {}", match_id, e);
+                report(
+                    &match_id,
+                    &status_tx,
+                    GameStatus::StartFailed(e.to_string()),
+                );
+                None
+            }
+        }
+    }
+
+    /// Advance the game by one step, unless it was cancelled
+    fn step(&mut self) -> Step {
+        let span = self.span.clone();
+        let _entered = span.enter();
+        if self.cancelled.load(Ordering::Relaxed) {
+            info!("Game {} was cancelled.", self.match_id);
+            return Step::Done(GameStatus::Error("Game was cancelled".to_string()));
+        }
+        // A panicking game must not take down the worker and the other games it runs
+        match panic::catch_unwind(AssertUnwindSafe(|| self.game.step())) {
+            Ok(Step::Progressed) => {
+                self.steps.fetch_add(1, Ordering::Relaxed);
+                Step::Progressed
+            }
+            Ok(step) => step,
+            Err(payload) => {
+                let error = GameError::Panicked(panic_message(payload.as_ref()));
+                error!("Game {} failed to advance: {}", self.match_id, error);
+                Step::Done(GameStatus::Error(error.to_string()))
+            }
+        }
+    }
+}
+
+/// A libmahjong game, along with its progress
+struct EngineGame {
+    match_id: String,
+    game_match: GameMatch,
+    seats: Vec<String>,
+    total_rounds: u64,
+    last_observed: Option<StateSnapshot>,
+}
+
+impl SteppedGame for EngineGame {
+    fn step(&mut self) -> Step {
+        match self.game_match.advance() {
+            Ok(AdvanceOutcome::Continued) => {
+                let observed = self.game_match.observe_state();
+                self.total_rounds += 1;
+                if self.total_rounds.rem(10) == 0 {
+                    info!(
+                        "Game {} advanced {} rounds. Current state: {:?}",
+                        self.match_id, self.total_rounds, observed
+                    );
+                }
+                if let Some(observed) = observed {
+                    let ended = observed.current_state() == StateFunctionType::GameEnd;
+                    self.last_observed = Some(StateSnapshot::from(&observed));
+                    if ended {
+                        return Step::Done(GameStatus::Finished(self.result()));
+                    }
+                }
+                Step::Progressed
+            }
+            Ok(AdvanceOutcome::AwaitingInput) => Step::Waiting,
+            Ok(AdvanceOutcome::Finished) => {
+                info!("Game {} finished.", self.match_id);
+                // The game is left in its GameEnd state, holding the final scores.
+                // When the engine ends the game without one, the last state seen stands.
+                if let Some(observed) = self.game_match.observe_state() {
+                    self.last_observed = Some(StateSnapshot::from(&observed));
+                }
+                Step::Done(GameStatus::Finished(self.result()))
+            }
+            Err(e) => {
+                error!("Game {} failed to advance: {}", self.match_id, e);
+                Step::Done(GameStatus::Error(e.to_string()))
+            }
+        }
+    }
+}
+
+impl EngineGame {
+    fn result(&mut self) -> GameResult {
+        GameResult {
+            seats: self.seats.clone(),
+            final_state: self.last_observed.take(),
+            history: self.game_match.history().map(|history| TurnLog {
+                turns: history.turns().map(StateSnapshot::from).collect(),
+                dropped: history.dropped(),
+            }),
+        }
+    }
+}
+
+/// Worker thread loop: take on new games and step every owned game in turn.
+/// Games that can progress are stepped back to back without pausing. The
+/// worker only blocks when it has no games, or when all of them are waiting
+/// on input, until it is given a new game or woken.
+fn run_worker(
+    event_rx: std_mpsc::Receiver<WorkerEvent>,
+    load: Arc<AtomicUsize>,
+    runner: Arc<dyn GameRunner>,
+) {
+    let mut games: Vec<RunningGame> = Vec::new();
+    let mut idle = false;
+
+    loop {
+        let mut events = Vec::new();
+        if games.is_empty() || idle {
+            match event_rx.recv() {
+                Ok(event) => events.push(event),
+                // Nothing can give new games or wake the waiting ones anymore
+                Err(_) => break,
+            }
+        }
+        events.extend(event_rx.try_iter());
+        for event in events {
+            // Being woken only ends the wait
+            let WorkerEvent::Job(job) = event else {
+                continue;
+            };
+            match RunningGame::start(runner.as_ref(), *job) {
+                Some(game) => games.push(game),
+                None => {
+                    load.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        idle = true;
+        games.retain_mut(|game| match game.step() {
+            Step::Progressed => {
+                idle = false;
+                true
+            }
+            Step::Waiting => true,
+            Step::Done(status) => {
+                let _entered = game.span.enter();
+                report(&game.match_id, &game.status_tx, status);
+                load.fetch_sub(1, Ordering::Relaxed);
+                false
+            }
+        });
+    }
+
+    for game in games {
+        let _entered = game.span.enter();
+        error!(
+            "Game worker stopped while game {} was waiting",
+            game.match_id
+        );
+        report(
+            &game.match_id,
+            &game.status_tx,
+            GameStatus::Error("Game worker stopped while the game was waiting".to_string()),
+        );
+    }
+}
+
+/// The message a panic was raised with, if it has one
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Send a game's final status back to the pool
+fn report(match_id: &str, status_tx: &mpsc::Sender<GameStatus>, status: GameStatus) {
+    if let Err(e) = status_tx.blocking_send(status) {
+        warn!(
+            "Could not send final status for game {}: receiver dropped. {}",
+            match_id, e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::seat_players;
+    use std::time::Duration;
+
+    /// Longest a test waits on a worker before failing
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Runner whose games wait on input forever
+    struct WaitingRunner;
+
+    struct WaitingGame;
+
+    impl GameRunner for WaitingRunner {
+        fn create(&self, _job: GameJob) -> Result<Box<dyn SteppedGame>, GameError> {
+            Ok(Box::new(WaitingGame))
+        }
+    }
+
+    impl SteppedGame for WaitingGame {
+        fn step(&mut self) -> Step {
+            Step::Waiting
+        }
+    }
+
+    /// Runner whose engine panics, while creating the game for `panics-on-create`
+    /// and on the first step for every other match
+    struct PanickingRunner;
+
+    struct PanickingGame;
+
+    impl GameRunner for PanickingRunner {
+        fn create(&self, job: GameJob) -> Result<Box<dyn SteppedGame>, GameError> {
+            if job.match_id == "panics-on-create" {
+                panic!("engine exploded");
+            }
+            Ok(Box::new(PanickingGame))
+        }
+    }
+
+    impl SteppedGame for PanickingGame {
+        fn step(&mut self) -> Step {
+            panic!("engine exploded");
+        }
+    }
+
+    fn job(match_id: &str) -> (GameJob, mpsc::Receiver<GameStatus>) {
+        let (status_tx, status_rx) = mpsc::channel(1);
+        let job = GameJob {
+            match_id: match_id.to_string(),
+            controllers: seat_players(&[], &[]),
+            seed: 0,
+            ruleset: Ruleset::default(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            steps: Arc::new(AtomicU64::new(0)),
+            status_tx,
+            states: None,
+            history: None,
+            span: Span::none(),
+        };
+        (job, status_rx)
+    }
+
+    async fn final_status(status_rx: &mut mpsc::Receiver<GameStatus>) -> GameStatus {
+        tokio::time::timeout(WAIT, status_rx.recv())
+            .await
+            .expect("no final status in time")
+            .expect("status sender dropped")
+    }
+
+    #[tokio::test]
+    async fn waking_stops_cancelled_waiting_game() {
+        let workers = WorkerPool::new(1, Arc::new(WaitingRunner)).unwrap();
+        let (job, mut status_rx) = job("match-1");
+        let cancelled = job.cancelled.clone();
+        let waker = workers.submit(job).unwrap();
+
+        cancelled.store(true, Ordering::Relaxed);
+        waker.wake();
+
+        let status = final_status(&mut status_rx).await;
+        assert!(matches!(status, GameStatus::Error(e) if e.contains("cancelled")));
+    }
+
+    #[tokio::test]
+    async fn panic_creating_game_fails_its_start() {
+        let workers = WorkerPool::new(1, Arc::new(PanickingRunner)).unwrap();
+        let (job, mut status_rx) = job("panics-on-create");
+        workers.submit(job).unwrap();
+
+        let status = final_status(&mut status_rx).await;
+        assert!(matches!(status, GameStatus::StartFailed(e) if e.contains("engine exploded")));
+    }
+
+    #[tokio::test]
+    async fn panic_stepping_game_ends_it_with_error() {
+        let workers = WorkerPool::new(1, Arc::new(PanickingRunner)).unwrap();
+        let (first, mut status_rx) = job("match-1");
+        workers.submit(first).unwrap();
+
+        let status = final_status(&mut status_rx).await;
+        assert!(matches!(status, GameStatus::Error(e) if e.contains("engine exploded")));
+
+        // The worker survives the panic and runs the next game
+        let (second, mut status_rx) = job("match-2");
+        workers.submit(second).unwrap();
+        let status = final_status(&mut status_rx).await;
+        assert!(matches!(status, GameStatus::Error(e) if e.contains("engine exploded")));
+    }
+
+    #[tokio::test]
+    async fn worker_ends_waiting_games_once_pool_is_gone() {
+        let workers = WorkerPool::new(1, Arc::new(WaitingRunner)).unwrap();
+        let (job, mut status_rx) = job("match-1");
+        let waker = workers.submit(job).unwrap();
+
+        drop(workers);
+        drop(waker);
+
+        let status = final_status(&mut status_rx).await;
+        assert!(matches!(status, GameStatus::Error(e) if e.contains("worker stopped")));
+    }
+}